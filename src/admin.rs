@@ -0,0 +1,379 @@
+use flow::{Config, State, Statistic};
+use futures::{future, Future};
+use hyper::{self, Error as HyperError, Method, StatusCode,
+            header::{Authorization, Bearer, ContentLength, ContentType}};
+use hyper::server::{Http, Request, Response, Service};
+use logger::{Level, Logger};
+use metrics::Metrics;
+use pool::{Pool, SharedFlow};
+use regex::{self, Regex};
+use ring::constant_time;
+use serde::Serialize;
+use serde_json;
+use std::{net::SocketAddr, sync::{mpsc, Arc}, thread};
+use url;
+use utils::BoxedFuture;
+
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct FlowInfo {
+    pub id: String,
+    pub config: Config,
+    pub statistic: Statistic,
+    pub state: State,
+    pub tail: u64,
+    pub next: u64,
+    pub age: u64,
+    pub idle: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ListResponse {
+    pub flows: Vec<FlowInfo>,
+    pub next: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SummaryResponse {
+    pub flows: usize,
+    pub pool_size: Option<usize>,
+    pub buffered_bytes: u64,
+    pub buffered_chunks: u64,
+}
+
+struct AdminService {
+    pool: Arc<Pool>,
+    token: Arc<String>,
+    metrics: Arc<Metrics>,
+    logger: Arc<Logger>,
+}
+
+type ResponseFuture = Box<Future<Item = Response, Error = HyperError> + Send>;
+
+impl AdminService {
+    fn check_authorization(&self, req: &Request) -> bool {
+        match req.headers().get::<Authorization<Bearer>>() {
+            Some(&Authorization(Bearer { ref token })) => {
+                constant_time::verify_slices_are_equal(token.as_bytes(), self.token.as_bytes())
+                    .is_ok()
+            }
+            None => false,
+        }
+    }
+
+    fn get_flow_info(flow_ptr: &SharedFlow) -> FlowInfo {
+        let flow = flow_ptr.read().unwrap();
+        let (tail, next) = flow.get_range();
+        FlowInfo {
+            id: flow.id.to_owned(),
+            config: flow.get_config().clone(),
+            statistic: flow.get_statistic().clone(),
+            state: flow.get_state().clone(),
+            tail,
+            next,
+            age: flow.get_created().elapsed().as_secs(),
+            idle: flow.get_active().elapsed().as_secs(),
+        }
+    }
+
+    fn response_json<T: Serialize>(data: &T) -> Response {
+        let body = serde_json::to_string(data).unwrap().into_bytes();
+        Response::new()
+            .with_header(ContentType::json())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body)
+    }
+
+    fn handle_list(&self, req: &Request) -> Response {
+        let mut after = None;
+        let mut limit = DEFAULT_LIST_LIMIT;
+        for (key, value) in url::form_urlencoded::parse(req.query().unwrap_or("").as_bytes()) {
+            match &*key {
+                "after" => after = Some(value.into_owned()),
+                "limit" => match value.parse::<usize>() {
+                    Ok(value) if value > 0 && value <= MAX_LIST_LIMIT => limit = value,
+                    _ => return Response::new().with_status(StatusCode::BadRequest),
+                },
+                _ => (),
+            }
+        }
+        let flows: Vec<_> = self.pool
+            .list(after.as_ref().map(|after| after.as_str()), limit)
+            .iter()
+            .map(Self::get_flow_info)
+            .collect();
+        let next = if flows.len() == limit {
+            flows.last().map(|info| info.id.to_owned())
+        } else {
+            None
+        };
+        Self::response_json(&ListResponse { flows, next })
+    }
+
+    fn handle_inspect(&self, route: regex::Captures) -> Response {
+        let flow_id = route.get(1).unwrap().as_str();
+        match self.pool.get(flow_id) {
+            Some(flow_ptr) => Self::response_json(&Self::get_flow_info(&flow_ptr)),
+            None => Response::new().with_status(StatusCode::NotFound),
+        }
+    }
+
+    fn handle_evict(&self, route: regex::Captures) -> Response {
+        let flow_id = route.get(1).unwrap().as_str();
+        match self.pool.evict(flow_id) {
+            Ok(_) => {
+                self.logger
+                    .log(Level::Info, "flow_evicted", json!({ "flow_id": flow_id }));
+                Response::new().with_header(ContentLength(0))
+            }
+            Err(_) => Response::new().with_status(StatusCode::NotFound),
+        }
+    }
+
+    fn handle_summary(&self) -> Response {
+        let usage = self.pool.get_usage();
+        Self::response_json(&SummaryResponse {
+            flows: self.pool.len(),
+            pool_size: self.pool.get_pool_size(),
+            buffered_bytes: usage.buffered_bytes,
+            buffered_chunks: usage.buffered_chunks,
+        })
+    }
+
+    fn handle_metrics(&self) -> Response {
+        let body = self.metrics.render(&self.pool).into_bytes();
+        Response::new()
+            .with_header(ContentType::plaintext())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body)
+    }
+}
+
+impl Service for AdminService {
+    type Request = Request;
+    type Response = Response;
+    type Error = HyperError;
+    type Future = ResponseFuture;
+
+    fn call(&self, req: Request) -> Self::Future {
+        lazy_static! {
+            static ref PATTERN_LIST: Regex = Regex::new(r"^/flows$").unwrap();
+            static ref PATTERN_FLOW: Regex = Regex::new(r"^/flows/([a-f0-9]{32})$").unwrap();
+            static ref PATTERN_SUMMARY: Regex = Regex::new(r"^/summary$").unwrap();
+            static ref PATTERN_METRICS: Regex = Regex::new(r"^/metrics$").unwrap();
+        }
+        if !self.check_authorization(&req) {
+            return future::ok(Response::new().with_status(StatusCode::Unauthorized)).boxed2();
+        }
+        let path = &req.path().to_owned();
+        let response = match req.method() {
+            &Method::Get => if PATTERN_LIST.is_match(path) {
+                self.handle_list(&req)
+            } else if let Some(route) = PATTERN_FLOW.captures(path) {
+                self.handle_inspect(route)
+            } else if PATTERN_SUMMARY.is_match(path) {
+                self.handle_summary()
+            } else if PATTERN_METRICS.is_match(path) {
+                self.handle_metrics()
+            } else {
+                Response::new().with_status(StatusCode::NotFound)
+            },
+            &Method::Delete => if let Some(route) = PATTERN_FLOW.captures(path) {
+                self.handle_evict(route)
+            } else {
+                Response::new().with_status(StatusCode::NotFound)
+            },
+            _ => Response::new().with_status(StatusCode::MethodNotAllowed),
+        };
+        future::ok(response).boxed2()
+    }
+}
+
+pub fn start_service(
+    addr: SocketAddr,
+    token: String,
+    pool: Arc<Pool>,
+    metrics: Arc<Metrics>,
+    logger: Arc<Logger>,
+) -> (SocketAddr, thread::JoinHandle<()>) {
+    let (addr_tx, addr_rx) = mpsc::channel();
+    let token = Arc::new(token);
+    let service_thd = thread::spawn(move || {
+        let service_logger = logger.clone();
+        let server = Http::<hyper::Chunk>::new()
+            .bind(&addr, move || {
+                Ok(AdminService {
+                    pool: pool.clone(),
+                    token: token.clone(),
+                    metrics: metrics.clone(),
+                    logger: service_logger.clone(),
+                })
+            })
+            .unwrap();
+        let bind_addr = server.local_addr().unwrap();
+        addr_tx.send(bind_addr).unwrap();
+        logger.log(
+            Level::Info,
+            "admin_started",
+            json!({ "addr": bind_addr.to_string() }),
+        );
+        server.run().unwrap();
+    });
+    (addr_rx.recv().unwrap(), service_thd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flow::{test_config, Flow};
+    use futures::Stream;
+    use hyper::client::Client;
+    use std::io;
+    use tokio::reactor::Core;
+
+    const TOKEN: &str = "c2VjcmV0LWFkbWluLXRva2Vu";
+    fn request(
+        prefix: &str,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let mut req = Request::new(method, format!("{}{}", prefix, path).parse().unwrap());
+        if let Some(token) = token {
+            req.headers_mut().set(Authorization(Bearer {
+                token: token.to_owned(),
+            }));
+        }
+        core.run(client.request(req).and_then(|res| {
+            let status_code = res.status();
+            res.body()
+                .concat2()
+                .and_then(move |body| Ok((status_code, body.to_vec())))
+        })).unwrap()
+    }
+
+    #[test]
+    fn admin_service() {
+        let pool_ptr = Pool::new(4, Some(16), None);
+        let (bind_addr, _) = start_service(
+            "127.0.0.1:0".parse().unwrap(),
+            TOKEN.to_owned(),
+            pool_ptr.clone(),
+            Metrics::new(1),
+            Logger::new(Level::Error, Box::new(io::sink())),
+        );
+        let prefix = &format!("http://127.0.0.1:{}", bind_addr.port());
+
+        let flows: Vec<_> = (0..3).map(|_| Flow::new(test_config())).collect();
+        for flow in flows.iter() {
+            pool_ptr.insert(flow.clone()).unwrap();
+        }
+        flows[0].write().unwrap().push("Hello".into()).wait().unwrap();
+        let mut flow_ids: Vec<_> = flows
+            .iter()
+            .map(|flow| flow.read().unwrap().id.to_owned())
+            .collect();
+        flow_ids.sort();
+
+        assert_eq!(
+            request(prefix, Method::Get, "/summary", None).0,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            request(prefix, Method::Get, "/summary", Some("fake")).0,
+            StatusCode::Unauthorized
+        );
+
+        let (status_code, body) = request(prefix, Method::Get, "/summary", Some(TOKEN));
+        assert_eq!(status_code, StatusCode::Ok);
+        assert_eq!(
+            serde_json::from_slice::<SummaryResponse>(&body).unwrap(),
+            SummaryResponse {
+                flows: 3,
+                pool_size: Some(16),
+                buffered_bytes: 5,
+                buffered_chunks: 1,
+            }
+        );
+
+        let (status_code, body) = request(prefix, Method::Get, "/flows?limit=2", Some(TOKEN));
+        assert_eq!(status_code, StatusCode::Ok);
+        let list = serde_json::from_slice::<ListResponse>(&body).unwrap();
+        assert_eq!(
+            list.flows
+                .iter()
+                .map(|info| info.id.to_owned())
+                .collect::<Vec<_>>(),
+            flow_ids[..2].to_vec()
+        );
+        assert_eq!(list.next, Some(flow_ids[1].to_owned()));
+
+        let (status_code, body) = request(
+            prefix,
+            Method::Get,
+            &format!("/flows?limit=2&after={}", flow_ids[1]),
+            Some(TOKEN),
+        );
+        assert_eq!(status_code, StatusCode::Ok);
+        let list = serde_json::from_slice::<ListResponse>(&body).unwrap();
+        assert_eq!(list.flows.len(), 1);
+        assert_eq!(list.flows[0].id, flow_ids[2]);
+        assert_eq!(list.next, None);
+
+        assert_eq!(
+            request(prefix, Method::Get, "/flows?limit=0", Some(TOKEN)).0,
+            StatusCode::BadRequest
+        );
+
+        let flow_id = flows[0].read().unwrap().id.to_owned();
+        let (status_code, body) = request(
+            prefix,
+            Method::Get,
+            &format!("/flows/{}", flow_id),
+            Some(TOKEN),
+        );
+        assert_eq!(status_code, StatusCode::Ok);
+        let info = serde_json::from_slice::<FlowInfo>(&body).unwrap();
+        assert_eq!(info.id, flow_id);
+        assert_eq!(info.config, test_config());
+        assert_eq!(
+            info.statistic,
+            Statistic {
+                pushed: 5,
+                dropped: 0,
+            }
+        );
+        assert_eq!(info.state, State::Streaming);
+        assert_eq!((info.tail, info.next), (0, 1));
+
+        let path = &format!("/flows/{}", flow_id);
+        assert_eq!(
+            request(prefix, Method::Delete, path, Some(TOKEN)).0,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            request(prefix, Method::Delete, path, Some(TOKEN)).0,
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            request(prefix, Method::Get, path, Some(TOKEN)).0,
+            StatusCode::NotFound
+        );
+        assert_eq!(flows[0].read().unwrap().get_state(), &State::Closed);
+        assert_eq!(pool_ptr.len(), 2);
+
+        assert_eq!(
+            request(prefix, Method::Get, "/metrics", None).0,
+            StatusCode::Unauthorized
+        );
+        let (status_code, body) = request(prefix, Method::Get, "/metrics", Some(TOKEN));
+        assert_eq!(status_code, StatusCode::Ok);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.lines().any(|line| line == "furakus_flows_evicted_total 1"));
+        assert!(body.lines().any(|line| line == "furakus_pool_flows 2"));
+    }
+}
@@ -1,9 +1,21 @@
 use ring::{digest, hmac, rand, rand::SecureRandom};
+use std::collections::HashSet;
 use utils;
 
 pub trait Authorizer: Send + Sync + 'static {
     fn sign(&self, flow_id: &str) -> String;
     fn verify(&self, flow_id: &str, token: &str) -> Result<(), ()>;
+
+    /// Check whether a client may create flows. `subject` is the verified client certificate.
+    fn verify_new(&self, _subject: Option<&str>) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// Check whether a client may write to a flow without a token. `owner` is the subject which
+    /// created the flow.
+    fn verify_subject(&self, _owner: Option<&str>, _subject: &str) -> Result<(), ()> {
+        Err(())
+    }
 }
 
 pub struct HMACAuthorizer {
@@ -34,6 +46,55 @@ impl Authorizer for HMACAuthorizer {
     }
 }
 
+/// Authorize the producers by their client certificates. Flows can only be created with a
+/// certificate, and only written by the same certificate subject or with the signed token.
+pub struct CertAuthorizer {
+    hmac: HMACAuthorizer,
+    // Allowed common names. Empty allows every certificate verified by the CA.
+    subjects: HashSet<String>,
+}
+
+impl CertAuthorizer {
+    pub fn new<I: IntoIterator<Item = String>>(subjects: I) -> Self {
+        CertAuthorizer {
+            hmac: HMACAuthorizer::new(),
+            subjects: subjects.into_iter().collect(),
+        }
+    }
+
+    fn check_subject(&self, subject: &str) -> Result<(), ()> {
+        if self.subjects.is_empty() || self.subjects.contains(subject) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl Authorizer for CertAuthorizer {
+    fn sign(&self, flow_id: &str) -> String {
+        self.hmac.sign(flow_id)
+    }
+
+    fn verify(&self, flow_id: &str, token: &str) -> Result<(), ()> {
+        self.hmac.verify(flow_id, token)
+    }
+
+    fn verify_new(&self, subject: Option<&str>) -> Result<(), ()> {
+        subject.ok_or(()).and_then(|subject| self.check_subject(subject))
+    }
+
+    fn verify_subject(&self, owner: Option<&str>, subject: &str) -> Result<(), ()> {
+        self.check_subject(subject).and_then(|_| {
+            if owner == Some(subject) {
+                Ok(())
+            } else {
+                Err(())
+            }
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -50,5 +111,27 @@ mod tests {
         assert_eq!(auth.verify(fake_id, token), Err(()));
         assert_eq!(auth.verify(flow_id, mal_token), Err(()));
         assert_eq!(auth.verify(flow_id, fake_token), Err(()));
+        assert_eq!(auth.verify_new(None), Ok(()));
+        assert_eq!(auth.verify_subject(Some("producer"), "producer"), Err(()));
+    }
+
+    #[test]
+    fn cert() {
+        let auth = CertAuthorizer::new(vec!["producer".to_owned(), "uploader".to_owned()]);
+        let flow_id = "bdc62e9323003d0f5cb44c8c745a0470";
+        assert_eq!(auth.verify_new(Some("producer")), Ok(()));
+        assert_eq!(auth.verify_new(Some("consumer")), Err(()));
+        assert_eq!(auth.verify_new(None), Err(()));
+        assert_eq!(auth.verify_subject(Some("producer"), "producer"), Ok(()));
+        assert_eq!(auth.verify_subject(Some("consumer"), "consumer"), Err(()));
+        // A permitted subject can't write to the flows of another one.
+        assert_eq!(auth.verify_subject(Some("producer"), "uploader"), Err(()));
+        assert_eq!(auth.verify_subject(None, "producer"), Err(()));
+        let token = &auth.sign(flow_id);
+        assert_eq!(auth.verify(flow_id, token), Ok(()));
+
+        let auth = CertAuthorizer::new(vec![]);
+        assert_eq!(auth.verify_new(Some("consumer")), Ok(()));
+        assert_eq!(auth.verify_new(None), Err(()));
     }
 }
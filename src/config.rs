@@ -0,0 +1,476 @@
+use flow;
+use listener::ListenAddrs;
+use logger::Level;
+use std::{env, collections::BTreeMap, fmt::Display, fs::File, io::Read, net::SocketAddr,
+          path::Path, str::FromStr};
+use tls::CertPair;
+use toml;
+
+const KEYS: [&str; 31] = [
+    "server_address",
+    "proxy_address",
+    "admin_address",
+    "admin_token",
+    "num_worker",
+    "num_shard",
+    "pool_size",
+    "deactive_timeout",
+    "max_ttl",
+    "meta_capacity",
+    "data_capacity",
+    "chunk_size",
+    "max_chunk_size",
+    "ready_threshold",
+    "shutdown_timeout",
+    "max_connections",
+    "max_connections_per_ip",
+    "header_timeout",
+    "connection_idle_timeout",
+    "min_throughput",
+    "max_rate_per_ip",
+    "max_concurrent_streams",
+    "log_level",
+    "log_file",
+    "tls_cert",
+    "tls_private",
+    "tls_pfx",
+    "tls_reload_interval",
+    "tls_client_ca",
+    "tls_client_subjects",
+    "config",
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server_address: ListenAddrs,
+    pub proxy_address: ListenAddrs,
+    pub admin_address: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    pub num_worker: usize,
+    pub num_shard: usize,
+    pub pool_size: usize,
+    pub deactive_timeout: u64,
+    pub max_ttl: u64,
+    pub meta_capacity: u64,
+    pub data_capacity: u64,
+    pub chunk_size: u64,
+    pub max_chunk_size: u64,
+    pub ready_threshold: f64,
+    pub shutdown_timeout: u64,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub header_timeout: u64,
+    pub connection_idle_timeout: u64,
+    pub min_throughput: u64,
+    pub max_rate_per_ip: u64,
+    pub max_concurrent_streams: u32,
+    pub log_level: Level,
+    pub log_file: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_private: Option<String>,
+    pub tls_pfx: Option<String>,
+    pub tls_reload_interval: u64,
+    pub tls_client_ca: Option<String>,
+    pub tls_client_subjects: Vec<String>,
+    // Tables must come last in TOML.
+    pub tls_sni: BTreeMap<String, CertPair>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_address: "0.0.0.0:3000".parse().unwrap(),
+            proxy_address: ListenAddrs::default(),
+            admin_address: None,
+            admin_token: None,
+            num_worker: 4,
+            num_shard: 16,
+            pool_size: 65536,
+            deactive_timeout: 3600,
+            max_ttl: 86400,
+            meta_capacity: 65536,
+            data_capacity: 1048576,
+            chunk_size: flow::DEFAULT_CHUNK_SIZE as u64,
+            max_chunk_size: 1048576,
+            ready_threshold: 0.9,
+            shutdown_timeout: 30,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            header_timeout: 10,
+            connection_idle_timeout: 60,
+            min_throughput: 0,
+            max_rate_per_ip: 0,
+            max_concurrent_streams: 100,
+            log_level: Level::Info,
+            log_file: None,
+            tls_cert: None,
+            tls_private: None,
+            tls_pfx: None,
+            tls_reload_interval: 60,
+            tls_client_ca: None,
+            tls_client_subjects: Vec::new(),
+            tls_sni: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub config_path: Option<String>,
+    pub print_config: bool,
+    pub overrides: Vec<(String, String)>,
+}
+
+pub const USAGE: &str = "Usage: furakus [--config <file>] [--print-config] [--<key> <value>]...
+
+Keys are the fields of the configuration file with '_' written as '-', for
+example --server-address 0.0.0.0:3000. Every key can also be set by the
+upper-cased environment variable, for example SERVER_ADDRESS.";
+
+/// Parse the command-line arguments, excluding the program name.
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        if arg == "--print-config" {
+            options.print_config = true;
+            continue;
+        }
+        if !arg.starts_with("--") {
+            return Err(format!("Unexpected argument '{}'.", arg));
+        }
+        let flag = &arg[2..];
+        let (key, value) = match flag.find('=') {
+            Some(pos) => (flag[..pos].replace('-', "_"), flag[pos + 1..].to_owned()),
+            None => match args.next() {
+                Some(value) => (flag.replace('-', "_"), value),
+                None => return Err(format!("Missing value for '{}'.", arg)),
+            },
+        };
+        if !KEYS.contains(&key.as_str()) {
+            return Err(format!("Unknown option '{}'.", arg));
+        }
+        if key == "config" {
+            options.config_path = Some(value);
+        } else {
+            options.overrides.push((key, value));
+        }
+    }
+    Ok(options)
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}' for {}.", value, key))
+}
+
+fn parse_optional(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+impl Config {
+    pub fn from_toml(content: &str) -> Result<Self, String> {
+        toml::from_str(content).map_err(|err| format!("Invalid configuration file: {}", err))
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|err| format!("Failed to read '{}': {}", path, err))?;
+        Self::from_toml(&content)
+    }
+
+    /// Load the configuration file, then apply the environment variables and the command-line
+    /// overrides, in that order.
+    pub fn load(options: &Options) -> Result<Self, String> {
+        let config_path = options
+            .config_path
+            .clone()
+            .or_else(|| env::var("CONFIG").ok());
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        for key in KEYS.iter().filter(|key| **key != "config") {
+            if let Ok(value) = env::var(key.to_uppercase()) {
+                config.set(key, &value)?;
+            }
+        }
+        for &(ref key, ref value) in options.overrides.iter() {
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Set a field from its string form. An empty value clears an optional field.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "server_address" => self.server_address = parse_value(key, value)?,
+            "proxy_address" => self.proxy_address = parse_value(key, value)?,
+            "admin_address" => {
+                self.admin_address = match parse_optional(value) {
+                    Some(value) => Some(parse_value(key, &value)?),
+                    None => None,
+                }
+            }
+            "admin_token" => self.admin_token = parse_optional(value),
+            "num_worker" => self.num_worker = parse_value(key, value)?,
+            "num_shard" => self.num_shard = parse_value(key, value)?,
+            "pool_size" => self.pool_size = parse_value(key, value)?,
+            "deactive_timeout" => self.deactive_timeout = parse_value(key, value)?,
+            "max_ttl" => self.max_ttl = parse_value(key, value)?,
+            "meta_capacity" => self.meta_capacity = parse_value(key, value)?,
+            "data_capacity" => self.data_capacity = parse_value(key, value)?,
+            "chunk_size" => self.chunk_size = parse_value(key, value)?,
+            "max_chunk_size" => self.max_chunk_size = parse_value(key, value)?,
+            "ready_threshold" => self.ready_threshold = parse_value(key, value)?,
+            "shutdown_timeout" => self.shutdown_timeout = parse_value(key, value)?,
+            "max_connections" => self.max_connections = parse_value(key, value)?,
+            "max_connections_per_ip" => self.max_connections_per_ip = parse_value(key, value)?,
+            "header_timeout" => self.header_timeout = parse_value(key, value)?,
+            "connection_idle_timeout" => {
+                self.connection_idle_timeout = parse_value(key, value)?
+            }
+            "min_throughput" => self.min_throughput = parse_value(key, value)?,
+            "max_rate_per_ip" => self.max_rate_per_ip = parse_value(key, value)?,
+            "max_concurrent_streams" => self.max_concurrent_streams = parse_value(key, value)?,
+            "log_level" => self.log_level = parse_value(key, value)?,
+            "log_file" => self.log_file = parse_optional(value),
+            "tls_cert" => self.tls_cert = parse_optional(value),
+            "tls_private" => self.tls_private = parse_optional(value),
+            "tls_pfx" => self.tls_pfx = parse_optional(value),
+            "tls_reload_interval" => self.tls_reload_interval = parse_value(key, value)?,
+            "tls_client_ca" => self.tls_client_ca = parse_optional(value),
+            "tls_client_subjects" => {
+                self.tls_client_subjects = value
+                    .split(',')
+                    .map(|subject| subject.trim())
+                    .filter(|subject| !subject.is_empty())
+                    .map(|subject| subject.to_owned())
+                    .collect()
+            }
+            _ => return Err(format!("Unknown configuration key '{}'.", key)),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        fn check<T: Display>(valid: bool, key: &str, value: T) -> Result<(), String> {
+            if valid {
+                Ok(())
+            } else {
+                Err(format!("Invalid value '{}' for {}.", value, key))
+            }
+        }
+        fn check_file(key: &str, path: &Option<String>) -> Result<(), String> {
+            match *path {
+                Some(ref path) if !Path::new(path).is_file() => {
+                    Err(format!("File '{}' for {} does not exist.", path, key))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        if self.server_address.0.is_empty() && self.proxy_address.0.is_empty() {
+            return Err("server_address or proxy_address is required.".into());
+        }
+        check(self.num_worker > 0, "num_worker", self.num_worker)?;
+        check(self.num_shard > 0, "num_shard", self.num_shard)?;
+        check(self.pool_size > 0, "pool_size", self.pool_size)?;
+        check(
+            self.deactive_timeout > 0,
+            "deactive_timeout",
+            self.deactive_timeout,
+        )?;
+        check(self.max_ttl > 0, "max_ttl", self.max_ttl)?;
+        check(
+            self.max_chunk_size >= flow::MIN_CHUNK_SIZE as u64,
+            "max_chunk_size",
+            self.max_chunk_size,
+        )?;
+        check(
+            self.chunk_size >= flow::MIN_CHUNK_SIZE as u64
+                && self.chunk_size <= self.max_chunk_size,
+            "chunk_size",
+            self.chunk_size,
+        )?;
+        check(
+            self.ready_threshold > 0.0 && self.ready_threshold <= 1.0,
+            "ready_threshold",
+            self.ready_threshold,
+        )?;
+        check(
+            self.max_concurrent_streams > 0,
+            "max_concurrent_streams",
+            self.max_concurrent_streams,
+        )?;
+        if self.admin_address.is_some() && self.admin_token.is_none() {
+            return Err("admin_token is required by admin_address.".into());
+        }
+        if self.tls_cert.is_some() != self.tls_private.is_some() {
+            return Err("tls_cert and tls_private must be set together.".into());
+        }
+        if self.tls_pfx.is_some() && self.tls_cert.is_some() {
+            return Err("tls_pfx and tls_cert are mutually exclusive.".into());
+        }
+        check_file("tls_cert", &self.tls_cert)?;
+        check_file("tls_private", &self.tls_private)?;
+        check_file("tls_pfx", &self.tls_pfx)?;
+        check_file("tls_client_ca", &self.tls_client_ca)?;
+        if self.tls_client_ca.is_some() && self.tls_cert.is_none() {
+            return Err("tls_client_ca requires tls_cert.".into());
+        }
+        if !self.tls_client_subjects.is_empty() && self.tls_client_ca.is_none() {
+            return Err("tls_client_subjects requires tls_client_ca.".into());
+        }
+        if !self.tls_sni.is_empty() && self.tls_cert.is_none() {
+            return Err("tls_sni requires tls_cert as the default certificate.".into());
+        }
+        for (name, pair) in self.tls_sni.iter() {
+            check_file(&format!("tls_sni.{}.cert", name), &Some(pair.cert.to_owned()))?;
+            check_file(
+                &format!("tls_sni.{}.private", name),
+                &Some(pair.private.to_owned()),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Render the configuration as TOML with the secrets masked.
+    pub fn to_toml(&self) -> String {
+        let mut config = self.clone();
+        if config.admin_token.is_some() {
+            config.admin_token = Some("********".into());
+        }
+        toml::to_string(&config).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(|arg| arg.to_owned()))
+    }
+
+    #[test]
+    fn command_line() {
+        assert_eq!(args(""), Ok(Options::default()));
+        assert_eq!(
+            args("--config furakus.toml --num-worker 8 --print-config --log-file="),
+            Ok(Options {
+                config_path: Some("furakus.toml".into()),
+                print_config: true,
+                overrides: vec![
+                    ("num_worker".into(), "8".into()),
+                    ("log_file".into(), "".into()),
+                ],
+            })
+        );
+        assert!(args("--num-workers 8").is_err());
+        assert!(args("--num-worker").is_err());
+        assert!(args("num-worker 8").is_err());
+    }
+
+    #[test]
+    fn load_file() {
+        let config = Config::from_toml(
+            r#"
+            server_address = "127.0.0.1:8080"
+            num_worker = 2
+            log_level = "debug"
+            tls_cert = "./tests/cert.pem"
+            tls_private = "./tests/private.pem"
+
+            [tls_sni."example.com"]
+            cert = "./tests/cert.pem"
+            private = "./tests/private.pem"
+            "#,
+        ).unwrap();
+        assert_eq!(config.server_address, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.num_worker, 2);
+        assert_eq!(config.log_level, Level::Debug);
+        assert_eq!(config.pool_size, Config::default().pool_size);
+        assert_eq!(
+            config.tls_sni.get("example.com"),
+            Some(&CertPair {
+                cert: "./tests/cert.pem".into(),
+                private: "./tests/private.pem".into(),
+            })
+        );
+        assert_eq!(config.validate(), Ok(()));
+        assert!(Config::from_toml("num_workers = 2").is_err());
+        assert!(Config::from_toml("num_worker = \"two\"").is_err());
+
+        let rendered = Config::from_toml(&config.to_toml()).unwrap();
+        assert_eq!(rendered, config);
+    }
+
+    #[test]
+    fn set_and_validate() {
+        let mut config = Config::default();
+        assert_eq!(config.validate(), Ok(()));
+        config.set("num_shard", "4").unwrap();
+        assert_eq!(config.num_shard, 4);
+        config.set("log_level", "warn").unwrap();
+        assert_eq!(config.log_level, Level::Warn);
+        assert!(config.set("num_shard", "four").is_err());
+        assert!(config.set("log_level", "fatal").is_err());
+        assert!(config.set("unknown", "1").is_err());
+
+        config.set("admin_address", "127.0.0.1:3001").unwrap();
+        assert!(config.validate().is_err());
+        config.set("admin_token", "secret").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        assert!(!config.to_toml().contains("secret"));
+
+        config.set("chunk_size", "512").unwrap();
+        assert!(config.validate().is_err());
+        config.set("chunk_size", "32768").unwrap();
+
+        config.set("max_concurrent_streams", "0").unwrap();
+        assert!(config.validate().is_err());
+        config.set("max_concurrent_streams", "16").unwrap();
+        assert_eq!(config.max_concurrent_streams, 16);
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set("tls_cert", "./tests/cert.pem").unwrap();
+        assert!(config.validate().is_err());
+        config.set("tls_private", "./tests/private.pem").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set("tls_private", "./tests/missing.pem").unwrap();
+        assert!(config.validate().is_err());
+        config.set("tls_private", "./tests/private.pem").unwrap();
+
+        config.set("tls_client_subjects", "alice, bob,").unwrap();
+        assert_eq!(config.tls_client_subjects, vec!["alice", "bob"]);
+        assert!(config.validate().is_err());
+        config.set("tls_client_ca", "./tests/cert.pem").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+        config.set("tls_client_subjects", "").unwrap();
+        assert!(config.tls_client_subjects.is_empty());
+
+        config.set("tls_cert", "").unwrap();
+        config.set("tls_private", "").unwrap();
+        assert!(config.validate().is_err());
+        config.set("tls_client_ca", "").unwrap();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.set("server_address", "").unwrap();
+        assert!(config.validate().is_err());
+        assert!(config.set("proxy_address", "localhost:3000").is_err());
+        config.set("proxy_address", "127.0.0.1:3000,unix:/run/furakus.sock").unwrap();
+        assert_eq!(config.proxy_address.0.len(), 2);
+        assert_eq!(config.validate(), Ok(()));
+        let rendered = Config::from_toml(&config.to_toml()).unwrap();
+        assert_eq!(rendered, config);
+    }
+}
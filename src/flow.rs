@@ -1,10 +1,15 @@
 use bytes::Bytes;
+use crc::crc32;
 use futures::{future, Future, sync::oneshot};
-use std::{error, fmt, mem, collections::{HashMap, VecDeque}, sync::{Arc, Mutex, RwLock, Weak}};
-use utils::BoxedFuture;
+use limits::TokenBucket;
+use ring::digest::{self, Context, SHA256};
+use std::{error, fmt, mem, collections::{BTreeMap, HashMap, VecDeque},
+          sync::{Arc, Mutex, RwLock, Weak}, time::{Duration, Instant}};
+use utils::{self, BoxedFuture};
 use uuid::Uuid;
 
-pub const REF_SIZE: usize = 32768;
+pub const DEFAULT_CHUNK_SIZE: usize = 32768;
+pub const MIN_CHUNK_SIZE: usize = 1024;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -12,6 +17,7 @@ pub enum Error {
     Dropped,
     NotReady,
     Eof,
+    Mismatch,
     Other,
 }
 
@@ -28,65 +34,182 @@ impl error::Error for Error {
             Error::Dropped => "Dropped",
             Error::NotReady => "NotReady",
             Error::Eof => "Eof",
+            Error::Mismatch => "Mismatch",
             Error::Other => "Other",
         }
     }
 }
 
+/// The checksums of a chunk in lowercase hex, computed once when it is pushed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub crc32c: String,
+    pub sha256: String,
+}
+
+impl Checksum {
+    fn new(data: &[u8]) -> Self {
+        Checksum {
+            crc32c: format!("{:08x}", crc32::checksum_castagnoli(data)),
+            sha256: utils::hex(digest::digest(&SHA256, data).as_ref()),
+        }
+    }
+}
+
+/// A buffered chunk, as listed for the consumers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub index: u64,
+    // The byte range of the chunk in the flow, end excluded.
+    pub start: u64,
+    pub end: u64,
+    pub length: u64,
+    pub pulled: u64,
+    pub checksum: Checksum,
+}
+
 #[derive(Debug)]
 pub enum Chunk {
-    Data(u64, Bytes),
-    Eof(u64),
+    Data {
+        pulled: u64,
+        // The byte offset in the flow, set when the chunk is pushed.
+        start: u64,
+        data: Bytes,
+        checksum: Checksum,
+    },
+    Eof {
+        pulled: u64,
+    },
 }
 
 impl Chunk {
-    fn data(data: Vec<u8>) -> Self {
-        Chunk::Data(0, Bytes::from(data))
+    /// Build a data chunk. Its checksums are computed here, so the producer can do it before
+    /// taking the flow lock.
+    pub fn data(data: Vec<u8>) -> Self {
+        let checksum = Checksum::new(&data);
+        Chunk::Data {
+            pulled: 0,
+            start: 0,
+            data: Bytes::from(data),
+            checksum,
+        }
     }
 
     fn eof() -> Self {
-        Chunk::Eof(0)
+        Chunk::Eof { pulled: 0 }
     }
 
     fn count(&self) -> u64 {
         match *self {
-            Chunk::Data(count, ..) => count,
-            Chunk::Eof(count, ..) => count,
+            Chunk::Data { pulled, .. } | Chunk::Eof { pulled } => pulled,
         }
     }
 
     fn len(&self) -> u64 {
         match *self {
-            Chunk::Data(_, ref data) => data.len() as u64,
-            Chunk::Eof(..) => 0,
+            Chunk::Data { ref data, .. } => data.len() as u64,
+            Chunk::Eof { .. } => 0,
         }
     }
 }
 
+impl From<Vec<u8>> for Chunk {
+    fn from(data: Vec<u8>) -> Self {
+        Chunk::data(data)
+    }
+}
+
+impl<'a> From<&'a str> for Chunk {
+    fn from(data: &'a str) -> Self {
+        Chunk::data(data.as_bytes().to_vec())
+    }
+}
+
 type SharedChunk = Arc<Mutex<Chunk>>;
 
 pub trait Observer: Send + Sync + 'static {
     fn on_active(&self, _flow: &Flow) {}
     fn on_close(&self, _flow: &Flow) {}
+    fn on_push(&self, _flow: &Flow, _len: u64) {}
+    fn on_drop(&self, _flow: &Flow, _len: u64) {}
+    fn on_block(&self, _flow: &Flow) {}
+    fn on_usage(&self, _flow: &Flow, _usage: &Usage) {}
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum State {
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum State {
     Streaming,
     Stop,
     Closed,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub length: Option<u64>,
     pub meta_capacity: u64,
     pub data_capacity: u64,
     pub keepcount: Option<u64>,
     pub preserve_mode: bool,
+    pub chunk_size: u64,
+    pub ttl: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    // Bytes per second, shared by all the pushes or all the pulls of the flow.
+    pub push_rate: Option<u64>,
+    pub pull_rate: Option<u64>,
+    // Set by the producer for the consumers.
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+    pub metadata: Option<BTreeMap<String, String>>,
+    // The expected SHA-256 of the data in lowercase hex, checked on EOF.
+    pub sha256: Option<String>,
+    // The client certificate subject which created the flow.
+    pub owner: Option<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            length: None,
+            meta_capacity: 0,
+            data_capacity: 0,
+            keepcount: None,
+            preserve_mode: false,
+            chunk_size: DEFAULT_CHUNK_SIZE as u64,
+            ttl: None,
+            idle_timeout: None,
+            push_rate: None,
+            pull_rate: None,
+            content_type: None,
+            filename: None,
+            metadata: None,
+            sha256: None,
+            owner: None,
+        }
+    }
+}
+
+/// The config shared by the tests, large enough that a few pushes never block.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    Config {
+        meta_capacity: 16777216,
+        data_capacity: 16777216,
+        keepcount: Some(1),
+        ..Default::default()
+    }
+}
+
+/// The resources held by a flow, reported to the observers whenever they change. A pull given up
+/// by its consumer is still counted as waiting until the next change.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub buffered_bytes: u64,
+    pub buffered_chunks: u64,
+    pub waiting_pushes: u64,
+    pub waiting_pulls: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Statistic {
     pub pushed: u64,
     pub dropped: u64,
@@ -98,6 +221,9 @@ pub struct Flow {
     config: Config,
     statistic: Statistic,
     state: State,
+    aborted: bool,
+    created: Instant,
+    active: Instant,
     next_index: u64,
     tail_index: u64,
     sanitize_index: u64,
@@ -106,6 +232,9 @@ pub struct Flow {
     waiting_push: VecDeque<(u64, u64, oneshot::Sender<()>)>,
     waiting_pull: Arc<Mutex<HashMap<u64, Vec<oneshot::Sender<SharedChunk>>>>>,
     observers: Vec<Box<Observer>>,
+    push_bucket: Option<Arc<TokenBucket>>,
+    pull_bucket: Option<Arc<TokenBucket>>,
+    hasher: Context,
 }
 
 type FlowFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
@@ -121,6 +250,8 @@ impl Flow {
         } else {
             (config.meta_capacity - 1) / *META_SIZE + 1
         };
+        let push_bucket = config.push_rate.map(TokenBucket::new);
+        let pull_bucket = config.pull_rate.map(TokenBucket::new);
         let flow = Flow {
             weakref: Weak::new(),
             id: Uuid::new_v4().simple().to_string(),
@@ -130,6 +261,9 @@ impl Flow {
                 dropped: 0,
             },
             state: State::Streaming,
+            aborted: false,
+            created: Instant::now(),
+            active: Instant::now(),
             next_index: 0,
             tail_index: 0,
             sanitize_index: 0,
@@ -138,6 +272,9 @@ impl Flow {
             waiting_push: VecDeque::new(),
             waiting_pull: Arc::new(Mutex::new(HashMap::new())),
             observers: Vec::new(),
+            push_bucket,
+            pull_bucket,
+            hasher: Context::new(&SHA256),
         };
         let flow_ptr = Arc::new(RwLock::new(flow));
         flow_ptr.write().unwrap().weakref = Arc::downgrade(&flow_ptr);
@@ -148,6 +285,35 @@ impl Flow {
         &self.config
     }
 
+    /// Take the content type of the first chunk, unless it is set on creation. Call it under the
+    /// same lock as the push of the chunk, so only the request pushing the first chunk sets it.
+    pub fn set_content_type(&mut self, content_type: String) {
+        if self.config.content_type.is_none() && self.next_index == 0 {
+            self.config.content_type = Some(content_type);
+        }
+    }
+
+    fn current_digest(&self) -> String {
+        utils::hex(self.hasher.clone().finish().as_ref())
+    }
+
+    /// The SHA-256 of the data in lowercase hex, once the flow has completely received it.
+    pub fn get_digest(&self) -> Option<String> {
+        if self.state == State::Streaming || self.aborted {
+            None
+        } else {
+            Some(self.current_digest())
+        }
+    }
+
+    pub fn get_push_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.push_bucket.clone()
+    }
+
+    pub fn get_pull_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.pull_bucket.clone()
+    }
+
     pub fn get_range(&self) -> (u64, u64) {
         (self.tail_index, self.next_index)
     }
@@ -156,6 +322,115 @@ impl Flow {
         &self.statistic
     }
 
+    pub fn get_state(&self) -> &State {
+        &self.state
+    }
+
+    /// List up to `limit` buffered data chunks, starting from the index `from`.
+    pub fn get_chunks(&self, from: u64, limit: usize) -> Vec<ChunkInfo> {
+        (from.max(self.tail_index)..self.next_index)
+            .filter_map(|index| {
+                self.bucket.get(&index).and_then(|chunk| match *chunk.lock().unwrap() {
+                    Chunk::Data {
+                        pulled,
+                        start,
+                        ref data,
+                        ref checksum,
+                    } => Some(ChunkInfo {
+                        index,
+                        start,
+                        end: start + data.len() as u64,
+                        length: data.len() as u64,
+                        pulled,
+                        checksum: checksum.clone(),
+                    }),
+                    Chunk::Eof { .. } => None,
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+
+    fn chunk_start(&self, chunk_index: u64) -> u64 {
+        match *self.bucket.get(&chunk_index).unwrap().lock().unwrap() {
+            Chunk::Data { start, .. } => start,
+            Chunk::Eof { .. } => self.statistic.pushed,
+        }
+    }
+
+    /// Find the chunk holding the byte at `offset`, and the offset of the byte in the chunk. Data
+    /// not pushed yet is located after the last chunk, relative to the next one.
+    pub fn locate(&self, offset: u64) -> Result<(u64, u64), Error> {
+        if self.aborted {
+            return Err(Error::Other);
+        }
+        if offset < self.statistic.dropped {
+            return Err(Error::Dropped);
+        }
+        if offset >= self.statistic.pushed {
+            if self.state != State::Streaming {
+                return Err(Error::Eof);
+            }
+            return Ok((self.next_index, offset - self.statistic.pushed));
+        }
+        // Offsets grow with indices, so search the last chunk starting at or before the byte.
+        let (mut low, mut high) = (self.tail_index, self.next_index);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if self.chunk_start(mid) <= offset {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Ok((low, offset - self.chunk_start(low)))
+    }
+
+    pub fn get_created(&self) -> Instant {
+        self.created
+    }
+
+    pub fn get_active(&self) -> Instant {
+        self.active
+    }
+
+    pub fn get_waiting(&self) -> (usize, usize) {
+        let waiting_pull = self.waiting_pull
+            .lock()
+            .unwrap()
+            .values()
+            .map(|waits| waits.iter().filter(|wait| !wait.is_canceled()).count())
+            .sum();
+        (self.waiting_push.len(), waiting_pull)
+    }
+
+    pub fn get_usage(&self) -> Usage {
+        let (waiting_pushes, waiting_pulls) = self.get_waiting();
+        Usage {
+            buffered_bytes: self.statistic.pushed - self.statistic.dropped,
+            buffered_chunks: self.bucket.len() as u64,
+            waiting_pushes: waiting_pushes as u64,
+            waiting_pulls: waiting_pulls as u64,
+        }
+    }
+
+    pub fn get_lifetime(&self) -> Option<Duration> {
+        fn remain(timeout: Option<u64>, since: Instant) -> Option<Duration> {
+            timeout.map(|timeout| {
+                Duration::from_secs(timeout)
+                    .checked_sub(since.elapsed())
+                    .unwrap_or(Duration::from_secs(0))
+            })
+        }
+        let ttl_remain = remain(self.config.ttl, self.created);
+        let idle_remain = remain(self.config.idle_timeout, self.active);
+        match (ttl_remain, idle_remain) {
+            (Some(ttl_remain), Some(idle_remain)) => Some(ttl_remain.min(idle_remain)),
+            (ttl_remain, None) => ttl_remain,
+            (None, idle_remain) => idle_remain,
+        }
+    }
+
     pub fn observe<T: Observer>(&mut self, observer: T) {
         self.observers.push(Box::new(observer));
     }
@@ -181,6 +456,16 @@ impl Flow {
         }
     }
 
+    fn notify_usage(&self) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let usage = self.get_usage();
+        for observer in self.observers.iter() {
+            observer.on_usage(&self, &usage);
+        }
+    }
+
     fn check_overflow(&self) -> bool {
         if self.statistic.pushed - self.statistic.dropped > self.config.data_capacity {
             return true;
@@ -191,7 +476,7 @@ impl Flow {
         false
     }
 
-    fn acquire_chunk(&mut self, chunk: Chunk) -> Result<(u64, u64, u64), Error> {
+    fn acquire_chunk(&mut self, mut chunk: Chunk) -> Result<(u64, u64, u64), Error> {
         let chunk_len = chunk.len() as u64;
         if chunk_len > self.config.data_capacity {
             return Err(Error::Invalid);
@@ -201,8 +486,15 @@ impl Flow {
         // then update consistently.
 
         let new_pushed = match chunk {
-            // EOF chunk ignores any overflow check.
-            Chunk::Eof(..) => self.statistic.pushed,
+            // EOF chunk ignores any overflow check, but not the digest.
+            Chunk::Eof { .. } => {
+                if let Some(ref sha256) = self.config.sha256 {
+                    if self.state == State::Streaming && *sha256 != self.current_digest() {
+                        return Err(Error::Mismatch);
+                    }
+                }
+                self.statistic.pushed
+            }
             _ => {
                 // Check if the flow is already overflow. Return if failed.
                 if self.check_overflow() {
@@ -225,8 +517,8 @@ impl Flow {
         };
         // Check and update state. Return if failed.
         if self.update_state(match chunk {
-            Chunk::Data(..) => State::Streaming,
-            Chunk::Eof(..) => State::Stop,
+            Chunk::Data { .. } => State::Streaming,
+            Chunk::Eof { .. } => State::Stop,
         }).is_err()
         {
             return Err(Error::Invalid);
@@ -238,6 +530,16 @@ impl Flow {
 
         // Update statistic.
         self.statistic.pushed = new_pushed;
+        // The chunk's own checksums come with it, only the digest of the flow is kept up here.
+        if let Chunk::Data {
+            ref mut start,
+            ref data,
+            ..
+        } = chunk
+        {
+            self.hasher.update(data);
+            *start = chunk_start;
+        }
 
         // Acquire the chunk index.
         let chunk_index = self.next_index;
@@ -255,7 +557,11 @@ impl Flow {
             }
         }
 
+        self.active = Instant::now();
         for observer in self.observers.iter() {
+            if chunk_len > 0 {
+                observer.on_push(&self, chunk_len);
+            }
             observer.on_active(&self);
         }
 
@@ -277,7 +583,7 @@ impl Flow {
                     break;
                 }
                 match *chunk {
-                    Chunk::Eof(..) => true,
+                    Chunk::Eof { .. } => true,
                     _ => false,
                 }
             };
@@ -301,6 +607,9 @@ impl Flow {
                         .len();
                     // Update statistic.
                     self.statistic.dropped += chunk_len;
+                    for observer in self.observers.iter() {
+                        observer.on_drop(&self, chunk_len);
+                    }
                 }
                 // Remove should always success.
                 self.bucket.remove(&self.tail_index).unwrap();
@@ -331,8 +640,7 @@ impl Flow {
         }
     }
 
-    pub fn push(&mut self, data: Vec<u8>) -> FlowFuture<u64> {
-        let chunk = Chunk::data(data);
+    pub fn push(&mut self, chunk: Chunk) -> FlowFuture<u64> {
         // Acquire the chunk. Return if failed.
         let (chunk_index, chunk_end) = match self.acquire_chunk(chunk) {
             Ok((chunk_index, _, chunk_end)) => (chunk_index, chunk_end),
@@ -344,14 +652,19 @@ impl Flow {
 
         // Atomically, automatically stop the flow if it reached the end.
         if let Some(length) = self.config.length {
-            if self.statistic.pushed >= length {
-                // Try to stop the flow.
-                self.acquire_chunk(Chunk::eof()).is_ok();
+            if self.statistic.pushed >= length && self.stop() == Err(Error::Mismatch) {
+                // Nothing more can be pushed to fix the data, so rather than leaving the consumers
+                // waiting on a flow which never completes, abort it.
+                self.expire();
+                return future::err(Error::Mismatch).boxed2();
             }
         }
 
         // Block for overflow.
         let fut = if is_overflow {
+            for observer in self.observers.iter() {
+                observer.on_block(&self);
+            }
             let (tx, rx) = oneshot::channel();
             self.waiting_push.push_back((chunk_index, chunk_end, tx));
             rx.map(move |_| chunk_index)
@@ -363,15 +676,70 @@ impl Flow {
 
         // Try to sanitize the buffer.
         self.sanitize_buffer();
+        self.notify_usage();
 
         fut
     }
 
+    pub fn expire(&mut self) {
+        // Pullers of an unfinished flow must not mistake the expiry for an EOF.
+        if self.state != State::Closed {
+            self.aborted = true;
+        }
+        // Dropping the senders wakes up the waiters with an error.
+        self.waiting_pull.lock().unwrap().clear();
+        self.waiting_push.clear();
+        // Release the buffered chunks.
+        let dropped_len = self.statistic.pushed - self.statistic.dropped;
+        self.bucket.clear();
+        self.tail_index = self.next_index;
+        self.sanitize_index = self.next_index;
+        self.statistic.dropped = self.statistic.pushed;
+        if dropped_len > 0 {
+            for observer in self.observers.iter() {
+                observer.on_drop(&self, dropped_len);
+            }
+        }
+        // Try to close the flow.
+        self.update_state(State::Closed).is_ok();
+        self.notify_usage();
+    }
+
+    /// Append the EOF. A flow not matching its expected digest is left streaming, so it never
+    /// looks complete. Its data stays available until the flow expires, except for fixed-length
+    /// flows, which are aborted by the push reaching their length.
+    fn stop(&mut self) -> Result<(), Error> {
+        let result = self.acquire_chunk(Chunk::eof()).map(|_| ());
+        if result.is_ok() {
+            self.notify_usage();
+        }
+        result
+    }
+
     pub fn close(&mut self) -> FlowFuture<()> {
-        future::result(self.acquire_chunk(Chunk::eof()).map(|_| ())).boxed2()
+        future::result(self.stop()).boxed2()
+    }
+
+    /// Close the flow, checking the SHA-256 given with the EOF in addition to the expected one.
+    pub fn close_with_digest(&mut self, sha256: &str) -> FlowFuture<()> {
+        if self.state == State::Streaming && sha256 != self.current_digest() {
+            return future::err(Error::Mismatch).boxed2();
+        }
+        self.close()
     }
 
     pub fn pull(&self, chunk_index: u64, timeout: Option<u64>) -> FlowFuture<Bytes> {
+        self.pull_chunk(chunk_index, timeout)
+            .map(|(data, _)| data)
+            .boxed2()
+    }
+
+    /// Pull the chunk along with its checksums.
+    pub fn pull_chunk(
+        &self,
+        chunk_index: u64,
+        timeout: Option<u64>,
+    ) -> FlowFuture<(Bytes, Checksum)> {
         // Clone the chunk if exists.
         let chunk = self.bucket.get(&chunk_index).map(|chunk| chunk.clone());
 
@@ -379,7 +747,9 @@ impl Flow {
         let fut = if let Some(chunk) = chunk {
             future::ok(chunk).boxed2()
         } else {
-            if self.state != State::Streaming {
+            if self.aborted {
+                future::err(Error::Other).boxed2()
+            } else if self.state != State::Streaming {
                 future::err(Error::Eof).boxed2()
             } else if chunk_index < self.next_index {
                 future::err(Error::Dropped).boxed2()
@@ -387,9 +757,13 @@ impl Flow {
                 future::err(Error::NotReady).boxed2()
             } else {
                 let (tx, rx) = oneshot::channel();
-                let mut waiting_pull = self.waiting_pull.lock().unwrap();
-                let waits = waiting_pull.entry(chunk_index).or_insert(Vec::new());
-                waits.push(tx);
+                self.waiting_pull
+                    .lock()
+                    .unwrap()
+                    .entry(chunk_index)
+                    .or_insert(Vec::new())
+                    .push(tx);
+                self.notify_usage();
                 rx.map_err(|_| Error::Other).boxed2()
             }
         };
@@ -405,8 +779,15 @@ impl Flow {
             let (count, result) = {
                 let mut chunk = chunk.lock().unwrap();
                 let (count, result) = match *chunk {
-                    Chunk::Data(ref mut count, ref data) => (count, Ok(data.clone())),
-                    Chunk::Eof(ref mut count) => (count, Err(Error::Eof)),
+                    Chunk::Data {
+                        pulled: ref mut count,
+                        ref data,
+                        ref checksum,
+                        ..
+                    } => (count, Ok((data.clone(), checksum.clone()))),
+                    Chunk::Eof {
+                        pulled: ref mut count,
+                    } => (count, Err(Error::Eof)),
                 };
                 *count += 1;
                 (*count, result)
@@ -420,6 +801,7 @@ impl Flow {
             } {
                 let mut flow = flow_ptr.write().unwrap();
                 flow.sanitize_buffer();
+                flow.notify_usage();
             }
 
             future::result(result)
@@ -430,14 +812,7 @@ impl Flow {
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    const FLOW_CONFIG: Config = Config {
-        length: None,
-        meta_capacity: 16777216,
-        data_capacity: 16777216,
-        keepcount: Some(1),
-        preserve_mode: false,
-    };
+    use std::thread;
 
     macro_rules! sync_assert_eq {
         ($a:expr, $b:expr) => {
@@ -448,7 +823,7 @@ mod tests {
 
     #[test]
     fn basic_operations() {
-        let ptr = Flow::new(FLOW_CONFIG);
+        let ptr = Flow::new(test_config());
         sync_assert_eq!(ptr.write().unwrap().push(vec![1u8; 1234].into()), Ok(0));
         sync_assert_eq!(ptr.write().unwrap().push("hello".into()), Ok(1));
         sync_assert_eq!(
@@ -463,10 +838,7 @@ mod tests {
     fn fixed_length_flow() {
         let ptr = Flow::new(Config {
             length: Some(10),
-            meta_capacity: 16777216,
-            data_capacity: 16777216,
-            keepcount: Some(1),
-            preserve_mode: false,
+            ..test_config()
         });
         sync_assert_eq!(ptr.write().unwrap().push("hello".into()), Ok(0));
         sync_assert_eq!(ptr.write().unwrap().push("world".into()), Ok(1));
@@ -476,7 +848,7 @@ mod tests {
 
     #[test]
     fn close_flow() {
-        let ptr = Flow::new(FLOW_CONFIG);
+        let ptr = Flow::new(test_config());
         sync_assert_eq!(ptr.write().unwrap().push("hello".into()), Ok(0));
         sync_assert_eq!(ptr.read().unwrap().pull(0, Some(0)), Ok("hello".into()));
         sync_assert_eq!(ptr.read().unwrap().pull(2, Some(0)), Err(Error::NotReady));
@@ -491,10 +863,7 @@ mod tests {
 
         let ptr = Flow::new(Config {
             length: Some(10),
-            meta_capacity: 16777216,
-            data_capacity: 16777216,
-            keepcount: Some(1),
-            preserve_mode: false,
+            ..test_config()
         });
         sync_assert_eq!(ptr.write().unwrap().push("hello".into()), Ok(0));
         sync_assert_eq!(ptr.write().unwrap().close(), Ok(()));
@@ -509,15 +878,14 @@ mod tests {
     #[test]
     fn dropped_chunk() {
         let ptr = Flow::new(Config {
-            length: None,
-            meta_capacity: REF_SIZE as u64 * 2,
-            data_capacity: REF_SIZE as u64 * 2,
+            meta_capacity: DEFAULT_CHUNK_SIZE as u64 * 2,
+            data_capacity: DEFAULT_CHUNK_SIZE as u64 * 2,
             keepcount: Some(2),
-            preserve_mode: false,
+            ..Default::default()
         });
-        let payload1 = vec![0u8; REF_SIZE];
-        let payload2 = vec![1u8; REF_SIZE];
-        let payload3 = vec![2u8; REF_SIZE];
+        let payload1 = vec![0u8; DEFAULT_CHUNK_SIZE];
+        let payload2 = vec![1u8; DEFAULT_CHUNK_SIZE];
+        let payload3 = vec![2u8; DEFAULT_CHUNK_SIZE];
 
         sync_assert_eq!(ptr.write().unwrap().push(payload1.clone().into()), Ok(0));
         sync_assert_eq!(ptr.write().unwrap().push(payload2.clone().into()), Ok(1));
@@ -559,15 +927,15 @@ mod tests {
     #[test]
     fn preserve_dropped_chunk() {
         let ptr = Flow::new(Config {
-            length: None,
-            meta_capacity: REF_SIZE as u64 * 2,
-            data_capacity: REF_SIZE as u64 * 2,
+            meta_capacity: DEFAULT_CHUNK_SIZE as u64 * 2,
+            data_capacity: DEFAULT_CHUNK_SIZE as u64 * 2,
             keepcount: Some(1),
             preserve_mode: true,
+            ..Default::default()
         });
-        let payload1 = vec![0u8; REF_SIZE];
-        let payload2 = vec![1u8; REF_SIZE];
-        let payload3 = vec![2u8; REF_SIZE + 1];
+        let payload1 = vec![0u8; DEFAULT_CHUNK_SIZE];
+        let payload2 = vec![1u8; DEFAULT_CHUNK_SIZE];
+        let payload3 = vec![2u8; DEFAULT_CHUNK_SIZE + 1];
 
         sync_assert_eq!(ptr.write().unwrap().push(payload1.clone().into()), Ok(0));
         sync_assert_eq!(ptr.write().unwrap().push(payload2.clone().into()), Ok(1));
@@ -603,7 +971,7 @@ mod tests {
 
     #[test]
     fn waiting_pull() {
-        let ptr = Flow::new(FLOW_CONFIG);
+        let ptr = Flow::new(test_config());
         sync_assert_eq!(ptr.read().unwrap().pull(0, Some(0)), Err(Error::NotReady));
         let fut = ptr.read().unwrap().pull(1, None);
         let fut1 = ptr.write().unwrap().push("ello".into());
@@ -613,17 +981,17 @@ mod tests {
 
     #[test]
     fn waiting_push() {
-        let ptr = Flow::new(FLOW_CONFIG);
-        let payload = vec![0u8; REF_SIZE];
+        let ptr = Flow::new(test_config());
+        let payload = vec![0u8; DEFAULT_CHUNK_SIZE];
 
         sync_assert_eq!(ptr.write().unwrap().push("A".into()), Ok(0));
-        for idx in 1..(FLOW_CONFIG.data_capacity / REF_SIZE as u64) {
+        for idx in 1..(test_config().data_capacity / DEFAULT_CHUNK_SIZE as u64) {
             sync_assert_eq!(ptr.write().unwrap().push(payload.clone().into()), Ok(idx));
         }
-        let base_idx = FLOW_CONFIG.data_capacity / REF_SIZE as u64;
+        let base_idx = test_config().data_capacity / DEFAULT_CHUNK_SIZE as u64;
         sync_assert_eq!(ptr.write().unwrap().push("D".into()), Ok(base_idx));
 
-        let fut = ptr.write().unwrap().push(vec![0u8; REF_SIZE].into());
+        let fut = ptr.write().unwrap().push(vec![0u8; DEFAULT_CHUNK_SIZE].into());
         sync_assert_eq!(ptr.write().unwrap().push("C".into()), Err(Error::NotReady));
         sync_assert_eq!(ptr.write().unwrap().close(), Ok(()));
         sync_assert_eq!(ptr.read().unwrap().pull(0, Some(0)), Ok("A".into()));
@@ -637,11 +1005,10 @@ mod tests {
     #[test]
     fn waiting_meta() {
         let ptr = Flow::new(Config {
-            length: None,
             meta_capacity: 4096,
             data_capacity: 65536,
             keepcount: Some(1),
-            preserve_mode: false,
+            ..Default::default()
         });
 
         for _ in 0..4096 {
@@ -666,7 +1033,7 @@ mod tests {
     #[test]
     fn outlive() {
         let fut = {
-            let ptr = Flow::new(FLOW_CONFIG);
+            let ptr = Flow::new(test_config());
             sync_assert_eq!(ptr.write().unwrap().push("A".into()), Ok(0));
             let flow = ptr.read().unwrap();
             flow.pull(0, Some(0))
@@ -675,11 +1042,8 @@ mod tests {
 
         let fut = {
             let ptr = Flow::new(Config {
-                length: None,
-                meta_capacity: 16777216,
                 data_capacity: 1,
-                keepcount: Some(1),
-                preserve_mode: false,
+                ..test_config()
             });
             sync_assert_eq!(ptr.write().unwrap().push("A".into()), Ok(0));
             let mut flow = ptr.write().unwrap();
@@ -690,7 +1054,7 @@ mod tests {
 
     #[test]
     fn observer() {
-        let ptr = Flow::new(FLOW_CONFIG);
+        let ptr = Flow::new(test_config());
 
         #[derive(Clone)]
         struct Ob(pub Arc<Mutex<u32>>);
@@ -745,7 +1109,7 @@ mod tests {
         }
 
         fn run_test(ptr: Arc<RwLock<Flow>>) {
-            let payload = vec![0u8; REF_SIZE];
+            let payload = vec![0u8; DEFAULT_CHUNK_SIZE];
             let ob1 = Ob::new();
             ptr.write().unwrap().observe(ob1.clone());
 
@@ -772,20 +1136,19 @@ mod tests {
         }
 
         let ptr = Flow::new(Config {
-            length: None,
-            meta_capacity: REF_SIZE as u64 * 16,
-            data_capacity: REF_SIZE as u64 * 16,
+            meta_capacity: DEFAULT_CHUNK_SIZE as u64 * 16,
+            data_capacity: DEFAULT_CHUNK_SIZE as u64 * 16,
             keepcount: None,
-            preserve_mode: false,
+            ..Default::default()
         });
         run_test(ptr);
 
         let ptr = Flow::new(Config {
-            length: None,
-            meta_capacity: REF_SIZE as u64 * 16,
-            data_capacity: REF_SIZE as u64 * 16,
+            meta_capacity: DEFAULT_CHUNK_SIZE as u64 * 16,
+            data_capacity: DEFAULT_CHUNK_SIZE as u64 * 16,
             keepcount: None,
             preserve_mode: true,
+            ..Default::default()
         });
         run_test(ptr);
     }
@@ -797,33 +1160,41 @@ mod tests {
             meta_capacity: 4096,
             data_capacity: 65536,
             keepcount: Some(18446744073709551615),
-            preserve_mode: false,
+            ..Default::default()
         };
         let ptr = Flow::new(config.clone());
         assert_eq!(ptr.read().unwrap().get_config(), &config);
 
         let config = Config {
-            length: None,
             meta_capacity: 18446744073709551615,
             data_capacity: 18446744073709551615,
             keepcount: None,
-            preserve_mode: false,
+            ..Default::default()
         };
         let ptr = Flow::new(config.clone());
         assert_eq!(ptr.read().unwrap().get_config(), &config);
+
+        // The content type is only taken before the first push, and never replaced.
+        ptr.write().unwrap().set_content_type("text/plain".into());
+        ptr.write().unwrap().set_content_type("text/html".into());
+        assert_eq!(
+            ptr.read().unwrap().get_config().content_type,
+            Some("text/plain".into())
+        );
+        let ptr = Flow::new(config.clone());
+        ptr.write().unwrap().push("A".into());
+        ptr.write().unwrap().set_content_type("text/plain".into());
+        assert_eq!(ptr.read().unwrap().get_config().content_type, None);
     }
 
     #[test]
     fn sanitize() {
         let ptr = Flow::new(Config {
-            length: None,
-            meta_capacity: 16777216,
-            data_capacity: REF_SIZE as u64 * 2,
-            keepcount: Some(1),
-            preserve_mode: false,
+            data_capacity: DEFAULT_CHUNK_SIZE as u64 * 2,
+            ..test_config()
         });
-        let payload1 = vec![0u8; REF_SIZE + 1];
-        let payload2 = vec![1u8; REF_SIZE + 2];
+        let payload1 = vec![0u8; DEFAULT_CHUNK_SIZE + 1];
+        let payload2 = vec![1u8; DEFAULT_CHUNK_SIZE + 2];
         sync_assert_eq!(ptr.write().unwrap().push(payload1.clone().into()), Ok(0));
         let fut = ptr.write().unwrap().push(payload2.clone().into());
         sync_assert_eq!(
@@ -832,13 +1203,12 @@ mod tests {
         );
         sync_assert_eq!(fut, Ok(1));
 
-        let payload3 = vec![2u8; REF_SIZE];
+        let payload3 = vec![2u8; DEFAULT_CHUNK_SIZE];
         let ptr = Flow::new(Config {
-            length: None,
             meta_capacity: 0,
             data_capacity: 16777216,
             keepcount: None,
-            preserve_mode: false,
+            ..Default::default()
         });
         for idx in 0..100 {
             sync_assert_eq!(ptr.write().unwrap().push(payload3.clone().into()), Ok(idx));
@@ -846,11 +1216,11 @@ mod tests {
         assert_eq!(ptr.read().unwrap().get_range(), (100, 100));
 
         let ptr = Flow::new(Config {
-            length: None,
             meta_capacity: 0,
             data_capacity: 16777216,
             keepcount: None,
             preserve_mode: true,
+            ..Default::default()
         });
         for idx in 0..100 {
             sync_assert_eq!(ptr.write().unwrap().push(payload3.clone().into()), Ok(idx));
@@ -861,11 +1231,8 @@ mod tests {
     #[test]
     fn chunk_size() {
         let ptr = Flow::new(Config {
-            length: None,
-            meta_capacity: 16777216,
             data_capacity: 0,
-            keepcount: Some(1),
-            preserve_mode: false,
+            ..test_config()
         });
         let payload = vec![0u8; 0];
         sync_assert_eq!(ptr.write().unwrap().push(payload.clone().into()), Ok(0));
@@ -880,4 +1247,200 @@ mod tests {
         );
         sync_assert_eq!(ptr.read().unwrap().pull(1, Some(0)), Err(Error::Eof));
     }
+
+    #[test]
+    fn expire() {
+        let ptr = Flow::new(test_config());
+        sync_assert_eq!(ptr.write().unwrap().push("A".into()), Ok(0));
+        let fut = ptr.read().unwrap().pull(1, None);
+        ptr.write().unwrap().expire();
+        sync_assert_eq!(fut, Err(Error::Other));
+        sync_assert_eq!(ptr.read().unwrap().pull(0, Some(0)), Err(Error::Other));
+        sync_assert_eq!(ptr.read().unwrap().pull(1, Some(0)), Err(Error::Other));
+        sync_assert_eq!(ptr.write().unwrap().push("B".into()), Err(Error::Invalid));
+        assert_eq!(ptr.read().unwrap().get_range(), (1, 1));
+        assert_eq!(
+            ptr.read().unwrap().get_statistic(),
+            &Statistic {
+                pushed: 1,
+                dropped: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn lifetime() {
+        let ptr = Flow::new(test_config());
+        assert_eq!(ptr.read().unwrap().get_lifetime(), None);
+
+        let ptr = Flow::new(Config {
+            ttl: Some(3),
+            idle_timeout: Some(2),
+            ..test_config()
+        });
+        let lifetime = ptr.read().unwrap().get_lifetime().unwrap();
+        assert!(lifetime <= Duration::from_secs(2) && lifetime > Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(1500));
+        sync_assert_eq!(ptr.write().unwrap().push("A".into()), Ok(0));
+        let lifetime = ptr.read().unwrap().get_lifetime().unwrap();
+        assert!(lifetime <= Duration::from_millis(1500) && lifetime > Duration::from_secs(1));
+        thread::sleep(Duration::from_secs(2));
+        assert_eq!(
+            ptr.read().unwrap().get_lifetime(),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn digest() {
+        const HELLO_WORLD: &str = "872e4e50ce9990d8b041330c47c9ddd11bec6b503ae9386a99da8584e9bb12c4";
+        let ptr = Flow::new(test_config());
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().push("World".into()), Ok(1));
+        assert_eq!(ptr.read().unwrap().get_digest(), None);
+        sync_assert_eq!(ptr.write().unwrap().close(), Ok(()));
+        assert_eq!(ptr.read().unwrap().get_digest(), Some(HELLO_WORLD.into()));
+
+        let ptr = Flow::new(test_config());
+        sync_assert_eq!(ptr.write().unwrap().push("HelloWorld".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().close_with_digest(HELLO_WORLD), Ok(()));
+
+        // A mismatched flow is left open rather than closed.
+        let ptr = Flow::new(test_config());
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(
+            ptr.write().unwrap().close_with_digest(HELLO_WORLD),
+            Err(Error::Mismatch)
+        );
+        assert_eq!(ptr.read().unwrap().get_state(), &State::Streaming);
+        assert_eq!(ptr.read().unwrap().get_digest(), None);
+        sync_assert_eq!(ptr.read().unwrap().pull(0, None), Ok("Hello".into()));
+        sync_assert_eq!(ptr.write().unwrap().close(), Ok(()));
+
+        let ptr = Flow::new(Config {
+            sha256: Some(HELLO_WORLD.into()),
+            ..test_config()
+        });
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().close(), Err(Error::Mismatch));
+        assert_eq!(ptr.read().unwrap().get_state(), &State::Streaming);
+        assert_eq!(ptr.read().unwrap().get_digest(), None);
+
+        // Fixed-length flows are checked when they stop by themselves.
+        let ptr = Flow::new(Config {
+            length: Some(10),
+            sha256: Some(HELLO_WORLD.into()),
+            ..test_config()
+        });
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().push("World".into()), Ok(1));
+        assert_eq!(ptr.read().unwrap().get_digest(), Some(HELLO_WORLD.into()));
+        let ptr = Flow::new(Config {
+            length: Some(10),
+            sha256: Some(HELLO_WORLD.into()),
+            ..test_config()
+        });
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(
+            ptr.write().unwrap().push("world".into()),
+            Err(Error::Mismatch)
+        );
+        sync_assert_eq!(ptr.read().unwrap().pull(0, None), Err(Error::Other));
+    }
+
+    #[test]
+    fn checksum() {
+        let ptr = Flow::new(test_config());
+        sync_assert_eq!(ptr.write().unwrap().push("123456789".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().push(vec![].into()), Ok(1));
+        sync_assert_eq!(
+            ptr.read().unwrap().pull_chunk(0, Some(0)),
+            Ok((
+                "123456789".into(),
+                Checksum {
+                    crc32c: "e3069283".into(),
+                    sha256: "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+                        .into(),
+                }
+            ))
+        );
+        sync_assert_eq!(
+            ptr.read().unwrap().pull_chunk(1, Some(0)),
+            Ok((
+                Bytes::new(),
+                Checksum {
+                    crc32c: "00000000".into(),
+                    sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                        .into(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn list_chunks() {
+        let ptr = Flow::new(test_config());
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().push("World!".into()), Ok(1));
+        sync_assert_eq!(ptr.write().unwrap().close(), Ok(()));
+        sync_assert_eq!(ptr.read().unwrap().pull(0, Some(0)), Ok("Hello".into()));
+        let hello = ChunkInfo {
+            index: 0,
+            start: 0,
+            end: 5,
+            length: 5,
+            pulled: 1,
+            checksum: Checksum::new(b"Hello"),
+        };
+        let world = ChunkInfo {
+            index: 1,
+            start: 5,
+            end: 11,
+            length: 6,
+            pulled: 0,
+            checksum: Checksum::new(b"World!"),
+        };
+        assert_eq!(
+            ptr.read().unwrap().get_chunks(0, 10),
+            vec![hello.clone(), world.clone()]
+        );
+        assert_eq!(ptr.read().unwrap().get_chunks(0, 1), vec![hello]);
+        assert_eq!(ptr.read().unwrap().get_chunks(1, 10), vec![world]);
+        assert_eq!(ptr.read().unwrap().get_chunks(2, 10), vec![]);
+        ptr.write().unwrap().expire();
+        assert_eq!(ptr.read().unwrap().get_chunks(0, 10), vec![]);
+    }
+
+    #[test]
+    fn locate() {
+        let ptr = Flow::new(Config {
+            keepcount: None,
+            data_capacity: 10,
+            ..test_config()
+        });
+        sync_assert_eq!(ptr.write().unwrap().push("Hello".into()), Ok(0));
+        sync_assert_eq!(ptr.write().unwrap().push("".into()), Ok(1));
+        sync_assert_eq!(ptr.write().unwrap().push("World".into()), Ok(2));
+        {
+            let flow = ptr.read().unwrap();
+            assert_eq!(flow.locate(0), Ok((0, 0)));
+            assert_eq!(flow.locate(4), Ok((0, 4)));
+            assert_eq!(flow.locate(5), Ok((2, 0)));
+            assert_eq!(flow.locate(9), Ok((2, 4)));
+            // Not pushed yet.
+            assert_eq!(flow.locate(10), Ok((3, 0)));
+            assert_eq!(flow.locate(12), Ok((3, 2)));
+        }
+        sync_assert_eq!(ptr.write().unwrap().push("!".into()), Ok(3));
+        sync_assert_eq!(ptr.write().unwrap().close(), Ok(()));
+        {
+            let flow = ptr.read().unwrap();
+            assert_eq!(flow.locate(0), Err(Error::Dropped));
+            assert_eq!(flow.locate(5), Ok((2, 0)));
+            assert_eq!(flow.locate(10), Ok((3, 0)));
+            assert_eq!(flow.locate(11), Err(Error::Eof));
+        }
+        ptr.write().unwrap().expire();
+        assert_eq!(ptr.read().unwrap().locate(5), Err(Error::Other));
+    }
 }
@@ -0,0 +1,202 @@
+use pool::Pool;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct HealthResponse {
+    pub alive: bool,
+    pub workers: Vec<bool>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    pub saturated: bool,
+    pub tls: bool,
+    pub draining: bool,
+}
+
+pub struct Health {
+    workers: Vec<AtomicBool>,
+    // Whether the TLS certificate is usable. Plaintext listeners always are, and only a failed
+    // certificate reload clears it.
+    tls: AtomicBool,
+    draining: AtomicBool,
+    transfers: AtomicUsize,
+    ready_threshold: f64,
+}
+
+/// Marks a worker as running until it is dropped, including by unwinding.
+pub struct WorkerGuard {
+    health: Arc<Health>,
+    idx: usize,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.health.workers[self.idx].store(false, Ordering::SeqCst);
+    }
+}
+
+/// Counts an in-flight push or pull until it is dropped.
+pub struct TransferGuard {
+    health: Arc<Health>,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.health.transfers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Health {
+    pub fn new(num_worker: usize, ready_threshold: f64) -> Arc<Self> {
+        Arc::new(Health {
+            workers: (0..num_worker).map(|_| AtomicBool::new(false)).collect(),
+            tls: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+            transfers: AtomicUsize::new(0),
+            ready_threshold,
+        })
+    }
+
+    pub fn start_worker(health: &Arc<Self>, idx: usize) -> WorkerGuard {
+        health.workers[idx].store(true, Ordering::SeqCst);
+        WorkerGuard {
+            health: health.clone(),
+            idx,
+        }
+    }
+
+    pub fn start_transfer(health: &Arc<Self>) -> TransferGuard {
+        health.transfers.fetch_add(1, Ordering::SeqCst);
+        TransferGuard {
+            health: health.clone(),
+        }
+    }
+
+    pub fn get_transfers(&self) -> usize {
+        self.transfers.load(Ordering::SeqCst)
+    }
+
+    /// Record the outcome of a TLS certificate reload.
+    pub fn set_tls(&self, tls: bool) {
+        self.tls.store(tls, Ordering::SeqCst);
+    }
+
+    pub fn set_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn check_alive(&self) -> HealthResponse {
+        let workers: Vec<_> = self.workers
+            .iter()
+            .map(|running| running.load(Ordering::SeqCst))
+            .collect();
+        HealthResponse {
+            alive: workers.iter().all(|running| *running),
+            workers,
+        }
+    }
+
+    pub fn check_ready(&self, pool: &Pool) -> ReadyResponse {
+        let saturated = match pool.get_pool_size() {
+            Some(pool_size) => pool.len() as f64 >= pool_size as f64 * self.ready_threshold,
+            None => false,
+        };
+        let tls = self.tls.load(Ordering::SeqCst);
+        let draining = self.is_draining();
+        ReadyResponse {
+            ready: !saturated && tls && !draining,
+            saturated,
+            tls,
+            draining,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flow::{test_config, Flow};
+
+    #[test]
+    fn alive() {
+        let health = Health::new(2, 1.0);
+        assert_eq!(health.check_alive().alive, false);
+        let guard0 = Health::start_worker(&health, 0);
+        {
+            let _guard1 = Health::start_worker(&health, 1);
+            assert_eq!(
+                health.check_alive(),
+                HealthResponse {
+                    alive: true,
+                    workers: vec![true, true],
+                }
+            );
+        }
+        assert_eq!(
+            health.check_alive(),
+            HealthResponse {
+                alive: false,
+                workers: vec![true, false],
+            }
+        );
+        drop(guard0);
+        assert_eq!(health.check_alive().workers, vec![false, false]);
+    }
+
+    #[test]
+    fn transfers() {
+        let health = Health::new(1, 1.0);
+        let guard0 = Health::start_transfer(&health);
+        let guard1 = Health::start_transfer(&health);
+        assert_eq!(health.get_transfers(), 2);
+        drop(guard0);
+        assert_eq!(health.get_transfers(), 1);
+        drop(guard1);
+        assert_eq!(health.get_transfers(), 0);
+    }
+
+    #[test]
+    fn ready() {
+        let pool_ptr = Pool::new(4, Some(4), None);
+        let health = Health::new(1, 0.5);
+        assert_eq!(health.check_ready(&pool_ptr).ready, true);
+        for _ in 0..2 {
+            pool_ptr.insert(Flow::new(test_config())).unwrap();
+        }
+        assert_eq!(
+            health.check_ready(&pool_ptr),
+            ReadyResponse {
+                ready: false,
+                saturated: true,
+                tls: true,
+                draining: false,
+            }
+        );
+
+        let pool_ptr = Pool::new(4, None, None);
+        assert_eq!(health.check_ready(&pool_ptr).ready, true);
+        health.set_draining();
+        assert_eq!(health.check_ready(&pool_ptr).ready, false);
+
+        // A failed certificate reload makes the service unready until a reload succeeds.
+        let health = Health::new(1, 1.0);
+        health.set_tls(false);
+        assert_eq!(
+            health.check_ready(&pool_ptr),
+            ReadyResponse {
+                ready: false,
+                saturated: false,
+                tls: false,
+                draining: false,
+            }
+        );
+        health.set_tls(true);
+        assert_eq!(health.check_ready(&pool_ptr).ready, true);
+    }
+}
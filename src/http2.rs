@@ -0,0 +1,233 @@
+use bytes::Bytes;
+use futures::{future, Async, Future, Poll, Sink, Stream, future::Either};
+use h2::{self, Reason, RecvStream, SendStream, server};
+use http;
+use hyper::{self, Body, Chunk, StatusCode, server::{Request, Response, Service}};
+use std::{cmp, mem, io::{self, Read, Write}, rc::Rc};
+use tokio::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The connection preface sent first by HTTP/2 clients.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// A stream which replays the bytes already read from it before reading further.
+pub struct Rewind<T> {
+    prefix: Bytes,
+    io: T,
+}
+
+impl<T: Read> Read for Rewind<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.io.read(buf);
+        }
+        let len = cmp::min(buf.len(), self.prefix.len());
+        buf[..len].copy_from_slice(&self.prefix.split_to(len));
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Rewind<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Rewind<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Rewind<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+pub struct DetectPreface<T> {
+    io: Option<T>,
+    buf: Vec<u8>,
+}
+
+/// Read the beginning of a plaintext connection to tell whether the client speaks HTTP/2 with
+/// prior knowledge. Resolves to the rewound stream and the result.
+pub fn detect_preface<T: AsyncRead>(io: T) -> DetectPreface<T> {
+    DetectPreface {
+        io: Some(io),
+        buf: Vec::with_capacity(PREFACE.len()),
+    }
+}
+
+impl<T: AsyncRead> Future for DetectPreface<T> {
+    type Item = (Rewind<T>, bool);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let len = self.buf.len();
+            // Stop at the first mismatch, so HTTP/1.1 requests are never held back.
+            if len == PREFACE.len() || self.buf[..] != PREFACE[..len] {
+                break;
+            }
+            let mut buf = [0u8; 24];
+            // Never read past the preface.
+            let ret = self.io
+                .as_mut()
+                .expect("poll after completion")
+                .read(&mut buf[..PREFACE.len() - len]);
+            match ret {
+                Ok(0) => break,
+                Ok(size) => self.buf.extend_from_slice(&buf[..size]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let is_http2 = &self.buf[..] == PREFACE;
+        let io = Rewind {
+            prefix: mem::replace(&mut self.buf, Vec::new()).into(),
+            io: self.io.take().expect("poll after completion"),
+        };
+        Ok(Async::Ready((io, is_http2)))
+    }
+}
+
+/// Forward a request body from HTTP/2 to the service, releasing the flow control window as the
+/// service consumes it.
+struct RecvBody {
+    stream: RecvStream,
+    done: bool,
+}
+
+impl Stream for RecvBody {
+    type Item = Result<Chunk, hyper::Error>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match self.stream.poll() {
+            Ok(Async::Ready(Some(data))) => {
+                self.stream.release_capacity().release_capacity(data.len()).ok();
+                Ok(Async::Ready(Some(Ok(data.into()))))
+            }
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // Abort the body so the service doesn't take a reset stream as complete.
+            Err(_) => {
+                self.done = true;
+                Ok(Async::Ready(Some(Err(hyper::Error::Incomplete))))
+            }
+        }
+    }
+}
+
+/// Send a response body over HTTP/2, waiting for the flow control window.
+struct SendBody {
+    body: Body,
+    stream: SendStream<Bytes>,
+    pending: Bytes,
+}
+
+impl Future for SendBody {
+    type Item = ();
+    type Error = h2::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if !self.pending.is_empty() {
+                self.stream.reserve_capacity(self.pending.len());
+                match self.stream.poll_capacity()? {
+                    Async::Ready(Some(capacity)) => {
+                        let len = cmp::min(capacity, self.pending.len());
+                        let data = self.pending.split_to(len);
+                        self.stream.send_data(data, false)?;
+                    }
+                    // The stream is reset by the client.
+                    Async::Ready(None) => return Ok(Async::Ready(())),
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+                continue;
+            }
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.pending = Bytes::from(chunk.as_ref()),
+                Ok(Async::Ready(None)) => {
+                    self.stream.send_data(Bytes::new(), true)?;
+                    return Ok(Async::Ready(()));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // Reset the stream so an aborted body doesn't look complete.
+                Err(_) => {
+                    self.stream.send_reset(Reason::INTERNAL_ERROR);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+fn into_http_response(response: Response) -> http::Response<Body> {
+    let mut response: http::Response<Body> = response.into();
+    {
+        // Connection-specific headers are forbidden in HTTP/2.
+        let headers = response.headers_mut();
+        headers.remove(http::header::CONNECTION);
+        headers.remove(http::header::TRANSFER_ENCODING);
+        headers.remove(http::header::UPGRADE);
+        headers.remove("keep-alive");
+    }
+    response
+}
+
+/// Serve an HTTP/2 connection. Every stream is handled by the service as a separate request on
+/// the reactor of `handle`.
+pub fn serve_connection<T, S>(
+    io: T,
+    service: S,
+    max_concurrent_streams: u32,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = h2::Error>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+    S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+{
+    let service = Rc::new(service);
+    let handle = handle.clone();
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(max_concurrent_streams)
+        .handshake::<_, Bytes>(io);
+    Box::new(handshake.and_then(move |connection| {
+        connection.for_each(move |(request, mut respond)| {
+            let (parts, stream) = request.into_parts();
+            let (tx, body) = Body::pair();
+            handle.spawn(
+                tx.send_all(RecvBody {
+                    stream,
+                    done: false,
+                }).then(|_| Ok(())),
+            );
+            let request = Request::from(http::Request::from_parts(parts, body));
+            handle.spawn(service.call(request).then(move |ret| {
+                let response = match ret {
+                    Ok(response) => response,
+                    Err(_) => Response::new().with_status(StatusCode::InternalServerError),
+                };
+                let (parts, body) = into_http_response(response).into_parts();
+                match respond.send_response(http::Response::from_parts(parts, ()), false) {
+                    Ok(stream) => Either::A(
+                        SendBody {
+                            body,
+                            stream,
+                            pending: Bytes::new(),
+                        }.map_err(|_| ()),
+                    ),
+                    Err(_) => Either::B(future::ok(())),
+                }
+            }));
+            Ok(())
+        })
+    }))
+}
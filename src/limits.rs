@@ -0,0 +1,632 @@
+use futures::{future, Async, Future, IntoFuture, Poll, Stream, sync::oneshot};
+use std::{collections::HashMap, io::{self, Read, Write}, net::IpAddr,
+          sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+use tokio::reactor::{Remote, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use utils::BoxedFuture;
+
+/// How long a client must have been waited on before its throughput is judged.
+const THROUGHPUT_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Violation {
+    MaxConnections,
+    MaxConnectionsPerIp,
+    HeaderTimeout,
+    IdleTimeout,
+    TooSlow,
+}
+
+pub const VIOLATIONS: [Violation; 5] = [
+    Violation::MaxConnections,
+    Violation::MaxConnectionsPerIp,
+    Violation::HeaderTimeout,
+    Violation::IdleTimeout,
+    Violation::TooSlow,
+];
+
+impl Violation {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Violation::MaxConnections => "max_connections",
+            Violation::MaxConnectionsPerIp => "max_connections_per_ip",
+            Violation::HeaderTimeout => "header_timeout",
+            Violation::IdleTimeout => "idle_timeout",
+            Violation::TooSlow => "too_slow",
+        }
+    }
+}
+
+fn as_millis(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1000 + duration.subsec_nanos() as usize / 1000000
+}
+
+pub struct Limits {
+    // Zero means unlimited.
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    header_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    // Bytes per second. Zero means unlimited.
+    min_throughput: u64,
+    max_rate_per_ip: u64,
+    connections: AtomicUsize,
+    connections_per_ip: Mutex<HashMap<IpAddr, (usize, Option<Arc<TokenBucket>>)>>,
+}
+
+/// Holds a connection slot until it is dropped.
+pub struct ConnectionGuard {
+    limits: Arc<Limits>,
+    addr: Option<IpAddr>,
+    bucket: Option<Arc<TokenBucket>>,
+}
+
+impl ConnectionGuard {
+    /// Take the slot of the client address, which behind a proxy is only known once the PROXY
+    /// header is read. Clients without an address, such as those on Unix domain sockets, are only
+    /// limited by the total.
+    pub fn charge(&mut self, addr: Option<IpAddr>) -> Result<(), Violation> {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        let limits = self.limits.clone();
+        let mut connections_per_ip = limits.connections_per_ip.lock().unwrap();
+        let max_rate_per_ip = limits.max_rate_per_ip;
+        let &mut (ref mut count, ref bucket) =
+            connections_per_ip.entry(addr).or_insert_with(|| {
+                let bucket = if max_rate_per_ip > 0 {
+                    Some(TokenBucket::new(max_rate_per_ip))
+                } else {
+                    None
+                };
+                (0, bucket)
+            });
+        if limits.max_connections_per_ip > 0 && *count >= limits.max_connections_per_ip {
+            return Err(Violation::MaxConnectionsPerIp);
+        }
+        *count += 1;
+        self.addr = Some(addr);
+        self.bucket = bucket.clone();
+        Ok(())
+    }
+
+    /// The bucket shared by all the transfers of the client address.
+    pub fn get_bucket(&self) -> Option<Arc<TokenBucket>> {
+        self.bucket.clone()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limits.connections.fetch_sub(1, Ordering::SeqCst);
+        let addr = match self.addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let mut connections_per_ip = self.limits.connections_per_ip.lock().unwrap();
+        let remove = match connections_per_ip.get_mut(&addr) {
+            Some(&mut (ref mut count, _)) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            connections_per_ip.remove(&addr);
+        }
+    }
+}
+
+impl Limits {
+    pub fn new(
+        max_connections: usize,
+        max_connections_per_ip: usize,
+        header_timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+        min_throughput: u64,
+        max_rate_per_ip: u64,
+    ) -> Arc<Self> {
+        Arc::new(Limits {
+            max_connections,
+            max_connections_per_ip,
+            header_timeout,
+            idle_timeout,
+            min_throughput,
+            max_rate_per_ip,
+            connections: AtomicUsize::new(0),
+            connections_per_ip: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Take a connection slot when the connection is accepted. The client address is charged
+    /// separately by `ConnectionGuard::charge`.
+    pub fn acquire(limits: &Arc<Self>) -> Result<ConnectionGuard, Violation> {
+        let connections = limits.connections.fetch_add(1, Ordering::SeqCst);
+        // The guard gives the slot back if the limit is reached.
+        let connection = ConnectionGuard {
+            limits: limits.clone(),
+            addr: None,
+            bucket: None,
+        };
+        if limits.max_connections > 0 && connections >= limits.max_connections {
+            return Err(Violation::MaxConnections);
+        }
+        Ok(connection)
+    }
+
+    pub fn get_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Check a connection against the timeouts and the minimum throughput.
+    pub fn check(&self, activity: &Activity) -> Option<Violation> {
+        let now = activity.now();
+        if activity.requests.load(Ordering::SeqCst) == 0 {
+            let head_since = activity.head_since.load(Ordering::SeqCst);
+            if let Some(header_timeout) = self.header_timeout {
+                let head_time = (now + 1).saturating_sub(head_since);
+                if head_since > 0 && head_time > as_millis(header_timeout) {
+                    return Some(Violation::HeaderTimeout);
+                }
+            }
+            let last_io = activity.last_io.load(Ordering::SeqCst);
+            if let Some(idle_timeout) = self.idle_timeout {
+                if now.saturating_sub(last_io) > as_millis(idle_timeout) {
+                    return Some(Violation::IdleTimeout);
+                }
+            }
+        }
+        if self.min_throughput > 0 {
+            let transfers = activity.transfers.lock().unwrap();
+            let too_slow = transfers.iter().any(|transfer| {
+                let waited = transfer.get_waited(now);
+                let transferred = transfer.transferred.load(Ordering::SeqCst) as u64;
+                waited >= as_millis(THROUGHPUT_GRACE)
+                    && transferred * 1000 < self.min_throughput * waited as u64
+            });
+            if too_slow {
+                return Some(Violation::TooSlow);
+            }
+        }
+        None
+    }
+}
+
+/// What a connection is doing, shared by its stream, its service and its watchdog. Times are
+/// milliseconds since the connection is accepted, offset by one where zero means unset.
+pub struct Activity {
+    base: Instant,
+    last_io: AtomicUsize,
+    head_since: AtomicUsize,
+    requests: AtomicUsize,
+    // An HTTP/2 connection may carry several transfers at once.
+    transfers: Mutex<Vec<Arc<Transfer>>>,
+}
+
+/// Marks a request in progress until it is dropped.
+pub struct RequestGuard {
+    activity: Arc<Activity>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.activity.requests.fetch_sub(1, Ordering::SeqCst);
+        // The connection is idle from the end of the request.
+        let now = self.activity.now();
+        self.activity.last_io.store(now, Ordering::SeqCst);
+    }
+}
+
+impl Activity {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Activity {
+            base: Instant::now(),
+            last_io: AtomicUsize::new(0),
+            // The first request head is due from the accept, even if the client never sends.
+            head_since: AtomicUsize::new(1),
+            requests: AtomicUsize::new(0),
+            transfers: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn now(&self) -> usize {
+        as_millis(self.base.elapsed())
+    }
+
+    fn on_read(&self) {
+        let now = self.now();
+        self.last_io.store(now, Ordering::SeqCst);
+        // The first bytes read while idle start the next request head.
+        if self.requests.load(Ordering::SeqCst) == 0 {
+            self.head_since
+                .compare_exchange(0, now + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .ok();
+        }
+    }
+
+    fn on_write(&self) {
+        self.last_io.store(self.now(), Ordering::SeqCst);
+    }
+
+    pub fn start_request(activity: &Arc<Self>) -> RequestGuard {
+        activity.requests.fetch_add(1, Ordering::SeqCst);
+        activity.head_since.store(0, Ordering::SeqCst);
+        RequestGuard {
+            activity: activity.clone(),
+        }
+    }
+}
+
+/// The time a transfer has spent waiting on the client, and the bytes it has moved. Times are
+/// those of the activity of its connection.
+struct Transfer {
+    wait_since: AtomicUsize,
+    waited: AtomicUsize,
+    transferred: AtomicUsize,
+}
+
+impl Transfer {
+    fn new() -> Arc<Self> {
+        Arc::new(Transfer {
+            wait_since: AtomicUsize::new(0),
+            waited: AtomicUsize::new(0),
+            transferred: AtomicUsize::new(0),
+        })
+    }
+
+    fn get_waited(&self, now: usize) -> usize {
+        let waited = self.waited.load(Ordering::SeqCst);
+        match self.wait_since.load(Ordering::SeqCst) {
+            0 => waited,
+            wait_since => waited + (now + 1).saturating_sub(wait_since),
+        }
+    }
+
+    fn start_wait(&self, now: usize) {
+        self.wait_since
+            .compare_exchange(0, now + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .ok();
+    }
+
+    fn end_wait(&self, now: usize) {
+        let wait_since = self.wait_since.swap(0, Ordering::SeqCst);
+        if wait_since > 0 {
+            self.waited.fetch_add((now + 1).saturating_sub(wait_since), Ordering::SeqCst);
+        }
+    }
+}
+
+/// A stream which reports the reads and writes to the activity of its connection.
+pub struct Monitored<T> {
+    io: T,
+    activity: Arc<Activity>,
+}
+
+impl<T> Monitored<T> {
+    pub fn new(io: T, activity: Arc<Activity>) -> Self {
+        Monitored { io, activity }
+    }
+}
+
+impl<T: Read> Read for Monitored<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.io.read(buf)?;
+        if len > 0 {
+            self.activity.on_read();
+        }
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for Monitored<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.io.write(buf)?;
+        self.activity.on_write();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Monitored<T> {}
+
+impl<T: AsyncWrite> AsyncWrite for Monitored<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+/// Measure the time a transfer spends waiting on the client, excluding the time it waits on the
+/// flow. Pushes wait on the client for the next piece of the body, while pulls wait on the client
+/// to take the previous piece.
+pub struct ClientWait<S: Stream> {
+    stream: S,
+    activity: Arc<Activity>,
+    transfer: Arc<Transfer>,
+    downstream: bool,
+    len: fn(&S::Item) -> usize,
+}
+
+impl<S: Stream> ClientWait<S> {
+    fn new(
+        stream: S,
+        activity: Arc<Activity>,
+        downstream: bool,
+        len: fn(&S::Item) -> usize,
+    ) -> Self {
+        let transfer = Transfer::new();
+        activity.transfers.lock().unwrap().push(transfer.clone());
+        ClientWait {
+            stream,
+            activity,
+            transfer,
+            downstream,
+            len,
+        }
+    }
+
+    fn start_wait(&self) {
+        self.transfer.start_wait(self.activity.now());
+    }
+
+    fn end_wait(&self) {
+        self.transfer.end_wait(self.activity.now());
+    }
+
+    pub fn upstream(stream: S, activity: Arc<Activity>, len: fn(&S::Item) -> usize) -> Self {
+        Self::new(stream, activity, false, len)
+    }
+
+    pub fn downstream(stream: S, activity: Arc<Activity>, len: fn(&S::Item) -> usize) -> Self {
+        Self::new(stream, activity, true, len)
+    }
+}
+
+impl<S: Stream> Stream for ClientWait<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.downstream {
+            // Being polled again means the client took the previous piece.
+            self.end_wait();
+        } else {
+            self.start_wait();
+        }
+        let ret = self.stream.poll();
+        match ret {
+            Ok(Async::Ready(Some(ref item))) => {
+                self.transfer
+                    .transferred
+                    .fetch_add((self.len)(item), Ordering::SeqCst);
+                if self.downstream {
+                    self.start_wait();
+                } else {
+                    self.end_wait();
+                }
+            }
+            Ok(Async::NotReady) => (),
+            _ => self.end_wait(),
+        }
+        ret
+    }
+}
+
+impl<S: Stream> Drop for ClientWait<S> {
+    fn drop(&mut self) {
+        let transfer = &self.transfer;
+        self.activity
+            .transfers
+            .lock()
+            .unwrap()
+            .retain(|other| !Arc::ptr_eq(other, transfer));
+    }
+}
+
+/// A token bucket refilled at a rate of bytes per second, holding up to a second of tokens.
+/// Takes may overdraw it, and the debt is paid by waiting.
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Arc<Self> {
+        Arc::new(TokenBucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        })
+    }
+
+    /// Take tokens for the bytes, returning how long to wait before sending them.
+    pub fn take(&self, len: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (ref mut tokens, ref mut last) = *state;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = self.rate as f64;
+        *tokens = (*tokens + elapsed * rate).min(rate) - len as f64;
+        *last = now;
+        if *tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let wait = -*tokens / rate;
+            Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+        }
+    }
+}
+
+/// Take tokens for the bytes from all the buckets, resolving once every bucket allows them.
+pub fn throttle(
+    remote: &Remote,
+    buckets: &[Arc<TokenBucket>],
+    len: u64,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let wait = buckets
+        .iter()
+        .map(|bucket| bucket.take(len))
+        .max()
+        .unwrap_or(Duration::from_secs(0));
+    if wait == Duration::from_secs(0) {
+        return future::ok(()).boxed2();
+    }
+    // Timeouts are bound to the reactor, so wait there and hand the result back.
+    let (tx, rx) = oneshot::channel();
+    remote.spawn(move |handle| {
+        Timeout::new(wait, handle)
+            .into_future()
+            .flatten()
+            .then(move |_| tx.send(()).or(Ok(())))
+    });
+    rx.map_err(|_| ()).boxed2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::thread;
+
+    fn connect(limits: &Arc<Limits>, addr: Option<IpAddr>) -> Result<ConnectionGuard, Violation> {
+        let mut connection = Limits::acquire(limits)?;
+        connection.charge(addr)?;
+        Ok(connection)
+    }
+
+    #[test]
+    fn connections() {
+        let limits = Limits::new(3, 2, None, None, 0, 0);
+        let addr1: IpAddr = "10.0.0.1".parse().unwrap();
+        let addr2: IpAddr = "10.0.0.2".parse().unwrap();
+        let guard1 = connect(&limits, Some(addr1)).unwrap();
+        let _guard2 = connect(&limits, Some(addr1)).unwrap();
+        assert_eq!(
+            connect(&limits, Some(addr1)).err(),
+            Some(Violation::MaxConnectionsPerIp)
+        );
+        let _guard3 = connect(&limits, Some(addr2)).unwrap();
+        assert_eq!(
+            connect(&limits, Some(addr2)).err(),
+            Some(Violation::MaxConnections)
+        );
+        assert_eq!(limits.get_connections(), 3);
+        drop(guard1);
+        assert!(connect(&limits, Some(addr1)).is_ok());
+        assert_eq!(limits.get_connections(), 2);
+        // Clients without an address only count toward the total.
+        let _guard4 = connect(&limits, None).unwrap();
+        assert_eq!(
+            connect(&limits, None).err(),
+            Some(Violation::MaxConnections)
+        );
+
+        // Behind a proxy, the connection is counted from the accept and its address later.
+        let limits = Limits::new(2, 1, None, None, 0, 0);
+        let mut connection1 = Limits::acquire(&limits).unwrap();
+        let mut connection2 = Limits::acquire(&limits).unwrap();
+        assert_eq!(
+            Limits::acquire(&limits).err(),
+            Some(Violation::MaxConnections)
+        );
+        assert_eq!(limits.get_connections(), 2);
+        connection1.charge(Some(addr1)).unwrap();
+        assert_eq!(
+            connection2.charge(Some(addr1)).err(),
+            Some(Violation::MaxConnectionsPerIp)
+        );
+        connection2.charge(Some(addr2)).unwrap();
+        drop(connection1);
+        assert_eq!(limits.get_connections(), 1);
+        assert!(connect(&limits, Some(addr1)).is_ok());
+    }
+
+    #[test]
+    fn timeouts() {
+        let limits = Limits::new(
+            0,
+            0,
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(300)),
+            0,
+            0,
+        );
+        let activity = Activity::new();
+        assert_eq!(limits.check(&activity), None);
+        // A client which never sends is timed from the accept.
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(limits.check(&activity), Some(Violation::HeaderTimeout));
+
+        let activity = Activity::new();
+        thread::sleep(Duration::from_millis(50));
+        activity.on_read();
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(limits.check(&activity), Some(Violation::HeaderTimeout));
+
+        let guard = Activity::start_request(&activity);
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(limits.check(&activity), None);
+        drop(guard);
+        assert_eq!(limits.check(&activity), None);
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(limits.check(&activity), Some(Violation::IdleTimeout));
+    }
+
+    #[test]
+    fn throughput() {
+        let limits = Limits::new(0, 0, None, None, 1000, 0);
+        let activity = Activity::new();
+        let mut body = ClientWait::upstream(
+            stream::iter_ok::<_, ()>(vec![vec![0u8; 4096], vec![0u8; 4096]]),
+            activity.clone(),
+            |chunk| chunk.len(),
+        );
+        body.poll().unwrap();
+        body.poll().unwrap();
+        assert_eq!(body.transfer.transferred.load(Ordering::SeqCst), 8192);
+        // Pretend the client has kept us waiting.
+        body.transfer.waited.store(8000, Ordering::SeqCst);
+        assert_eq!(limits.check(&activity), None);
+        body.transfer.waited.store(as_millis(THROUGHPUT_GRACE), Ordering::SeqCst);
+        assert_eq!(limits.check(&activity), Some(Violation::TooSlow));
+
+        // Another transfer on the same connection keeps its own accounting.
+        let other = ClientWait::downstream(
+            stream::iter_ok::<_, ()>(vec![vec![0u8; 4096]]),
+            activity.clone(),
+            |chunk| chunk.len(),
+        );
+        assert_eq!(body.transfer.transferred.load(Ordering::SeqCst), 8192);
+        assert_eq!(limits.check(&activity), Some(Violation::TooSlow));
+        drop(body);
+        assert_eq!(limits.check(&activity), None);
+        drop(other);
+        assert!(activity.transfers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        // A second of tokens is available at once.
+        assert_eq!(bucket.take(1000), Duration::from_secs(0));
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(bucket.take(50), Duration::from_secs(0));
+
+        let limits = Limits::new(0, 0, None, None, 0, 1000);
+        let addr1: IpAddr = "10.0.0.1".parse().unwrap();
+        let addr2: IpAddr = "10.0.0.2".parse().unwrap();
+        let guard1 = connect(&limits, Some(addr1)).unwrap();
+        let guard2 = connect(&limits, Some(addr1)).unwrap();
+        let guard3 = connect(&limits, Some(addr2)).unwrap();
+        let bucket1 = guard1.get_bucket().unwrap();
+        assert!(Arc::ptr_eq(&bucket1, &guard2.get_bucket().unwrap()));
+        assert!(!Arc::ptr_eq(&bucket1, &guard3.get_bucket().unwrap()));
+        let unlimited = Limits::new(0, 0, None, None, 0, 0);
+        assert!(connect(&unlimited, Some(addr1)).unwrap().get_bucket().is_none());
+    }
+}
@@ -0,0 +1,261 @@
+use futures::Poll;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, io::{self, Read, Write}, net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+          path::PathBuf, str::FromStr};
+#[cfg(unix)]
+use std::{fs, os::unix::{fs::FileTypeExt, net as unix}};
+use tokio::{net::TcpStream, reactor::Handle};
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio_uds::UnixStream;
+
+/// A TCP address, or the path of a Unix domain socket written as `unix:<path>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        if addr.starts_with("unix:") {
+            let path = &addr["unix:".len()..];
+            if path.is_empty() {
+                return Err(format!("Invalid address '{}'.", addr));
+            }
+            Ok(ListenAddr::Unix(path.into()))
+        } else {
+            addr.parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| format!("Invalid address '{}'.", addr))
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A comma-separated list of addresses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListenAddrs(pub Vec<ListenAddr>);
+
+impl FromStr for ListenAddrs {
+    type Err = String;
+
+    fn from_str(addrs: &str) -> Result<Self, Self::Err> {
+        addrs
+            .split(',')
+            .map(|addr| addr.trim())
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| addr.parse())
+            .collect::<Result<_, _>>()
+            .map(ListenAddrs)
+    }
+}
+
+impl fmt::Display for ListenAddrs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addrs: Vec<_> = self.0.iter().map(|addr| addr.to_string()).collect();
+        write!(f, "{}", addrs.join(","))
+    }
+}
+
+impl Serialize for ListenAddrs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddrs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+pub enum Listener {
+    Tcp(net::TcpListener),
+    #[cfg(unix)]
+    Unix(unix::UnixListener),
+}
+
+impl Listener {
+    pub fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match *addr {
+            ListenAddr::Tcp(ref addr) => net::TcpListener::bind(addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            ListenAddr::Unix(ref path) => {
+                // Replace the socket left behind by a previous run, but never a regular file.
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                unix::UnixListener::bind(path).map(Listener::Unix)
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix domain sockets are not supported",
+            )),
+        }
+    }
+
+    /// The bound address, with the port assigned by the system.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => listener.local_addr().and_then(|addr| {
+                addr.as_pathname()
+                    .map(|path| ListenAddr::Unix(path.to_owned()))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Unnamed socket"))
+            }),
+        }
+    }
+
+    /// Accept a connection and the address of its peer. Unix domain sockets have no peer
+    /// address.
+    pub fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match *self {
+            Listener::Tcp(ref listener) => listener
+                .accept()
+                .map(|(io, addr)| (Stream::Tcp(io), Some(addr))),
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => {
+                listener.accept().map(|(io, _)| (Stream::Unix(io), None))
+            }
+        }
+    }
+}
+
+/// Connect to a listener so a blocking accept returns.
+pub fn wake(addr: &ListenAddr) {
+    match *addr {
+        ListenAddr::Tcp(mut addr) => {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                });
+            }
+            net::TcpStream::connect(addr).ok();
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(ref path) => {
+            unix::UnixStream::connect(path).ok();
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => (),
+    }
+}
+
+/// An accepted connection, before it is registered on the reactor of a worker.
+pub enum Stream {
+    Tcp(net::TcpStream),
+    #[cfg(unix)]
+    Unix(unix::UnixStream),
+}
+
+impl Stream {
+    pub fn into_async(self, handle: &Handle) -> io::Result<AsyncStream> {
+        match self {
+            Stream::Tcp(io) => TcpStream::from_stream(io, handle).map(AsyncStream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(io) => UnixStream::from_stream(io, handle).map(AsyncStream::Unix),
+        }
+    }
+}
+
+pub enum AsyncStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncStream {
+    /// Set the send buffer size of TCP connections. Unix domain sockets are left as they are.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        match *self {
+            AsyncStream::Tcp(ref io) => io.set_send_buffer_size(size),
+            #[cfg(unix)]
+            AsyncStream::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for AsyncStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            AsyncStream::Tcp(ref mut io) => io.read(buf),
+            #[cfg(unix)]
+            AsyncStream::Unix(ref mut io) => io.read(buf),
+        }
+    }
+}
+
+impl Write for AsyncStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            AsyncStream::Tcp(ref mut io) => io.write(buf),
+            #[cfg(unix)]
+            AsyncStream::Unix(ref mut io) => io.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            AsyncStream::Tcp(ref mut io) => io.flush(),
+            #[cfg(unix)]
+            AsyncStream::Unix(ref mut io) => io.flush(),
+        }
+    }
+}
+
+impl AsyncRead for AsyncStream {}
+
+impl AsyncWrite for AsyncStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            AsyncStream::Tcp(ref mut io) => AsyncWrite::shutdown(io),
+            #[cfg(unix)]
+            AsyncStream::Unix(ref mut io) => AsyncWrite::shutdown(io),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addrs() {
+        let addrs: ListenAddrs = "127.0.0.1:3000, [::1]:3000,unix:/run/furakus.sock,"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            addrs,
+            ListenAddrs(vec![
+                ListenAddr::Tcp("127.0.0.1:3000".parse().unwrap()),
+                ListenAddr::Tcp("[::1]:3000".parse().unwrap()),
+                ListenAddr::Unix("/run/furakus.sock".into()),
+            ])
+        );
+        assert_eq!(
+            addrs.to_string(),
+            "127.0.0.1:3000,[::1]:3000,unix:/run/furakus.sock"
+        );
+        assert_eq!("".parse(), Ok(ListenAddrs(Vec::new())));
+        assert!("127.0.0.1".parse::<ListenAddrs>().is_err());
+        assert!("unix:".parse::<ListenAddrs>().is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use flow::{Flow, Observer};
+use serde_json::{self, Map, Value};
+use std::{thread, fs::OpenOptions, io::{self, Write}, str::FromStr, sync::{mpsc, Arc, Mutex},
+          time::{SystemTime, UNIX_EPOCH}};
+
+// Lines waiting for the writer thread. Beyond that, lines are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+enum Message {
+    Line(Vec<u8>),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Lines are written by a dedicated thread, so logging never blocks on the output.
+pub struct Logger {
+    level: Level,
+    sender: Mutex<mpsc::SyncSender<Message>>,
+}
+
+impl Logger {
+    pub fn new(level: Level, output: Box<Write + Send>) -> Arc<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::spawn(move || Self::write_lines(receiver, output));
+        Arc::new(Logger {
+            level,
+            sender: Mutex::new(sender),
+        })
+    }
+
+    fn write_lines(receiver: mpsc::Receiver<Message>, mut output: Box<Write + Send>) {
+        // Logging must never take the server down.
+        while let Ok(message) = receiver.recv() {
+            let mut next = Some(message);
+            while let Some(message) = next {
+                match message {
+                    Message::Line(line) => {
+                        output.write_all(&line).ok();
+                    }
+                    Message::Flush(done) => {
+                        output.flush().ok();
+                        done.send(()).ok();
+                    }
+                }
+                next = receiver.try_recv().ok();
+            }
+            // Flush once the queue is drained.
+            output.flush().ok();
+        }
+    }
+
+    pub fn stdout(level: Level) -> Arc<Self> {
+        Self::new(level, Box::new(io::stdout()))
+    }
+
+    pub fn open(level: Level, path: &str) -> io::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(level, Box::new(file)))
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    /// Write an event as a single JSON line. `fields` must be a JSON object.
+    pub fn log(&self, level: Level, event: &str, fields: Value) {
+        if !self.enabled(level) {
+            return;
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut record = Map::new();
+        record.insert(
+            "ts".into(),
+            json!(timestamp.as_secs() as f64 + timestamp.subsec_nanos() as f64 / 1e9),
+        );
+        record.insert("level".into(), json!(level.name()));
+        record.insert("event".into(), json!(event));
+        if let Value::Object(fields) = fields {
+            record.extend(fields);
+        }
+        let mut line = serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        // Rather drop the line than wait for a stalled output.
+        self.sender
+            .lock()
+            .unwrap()
+            .try_send(Message::Line(line))
+            .ok();
+    }
+
+    /// Block until the lines logged so far are written.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        let queued = self.sender.lock().unwrap().send(Message::Flush(done)).is_ok();
+        if queued {
+            wait.recv().ok();
+        }
+    }
+}
+
+impl Observer for Arc<Logger> {
+    fn on_close(&self, flow: &Flow) {
+        let statistic = flow.get_statistic();
+        self.log(
+            Level::Info,
+            "flow_closed",
+            json!({
+                "flow_id": flow.id,
+                "pushed": statistic.pushed,
+                "dropped": statistic.dropped,
+                "age": flow.get_created().elapsed().as_secs(),
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flow::test_config;
+
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn records(&self) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn level() {
+        assert_eq!("warn".parse(), Ok(Level::Warn));
+        assert_eq!("fatal".parse::<Level>(), Err(()));
+
+        let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let logger = Logger::new(Level::Warn, Box::new(buffer.clone()));
+        assert!(logger.enabled(Level::Error));
+        assert!(!logger.enabled(Level::Info));
+        logger.log(Level::Info, "ignored", json!({}));
+        logger.log(Level::Error, "failed", json!({ "worker": 1 }));
+        logger.flush();
+
+        let records = buffer.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["level"], "error");
+        assert_eq!(records[0]["event"], "failed");
+        assert_eq!(records[0]["worker"], 1);
+        assert!(records[0]["ts"].is_f64());
+    }
+
+    #[test]
+    fn flow_events() {
+        let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let logger = Logger::new(Level::Info, Box::new(buffer.clone()));
+        let flow_ptr = Flow::new(test_config());
+        flow_ptr.write().unwrap().observe(logger.clone());
+        flow_ptr.write().unwrap().push("Hello".into());
+        flow_ptr.write().unwrap().expire();
+        logger.flush();
+
+        let records = buffer.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["event"], "flow_closed");
+        assert_eq!(records[0]["flow_id"], flow_ptr.read().unwrap().id);
+        assert_eq!(records[0]["pushed"], 5);
+    }
+}
@@ -1,6 +1,10 @@
+extern crate base64;
 extern crate bytes;
+extern crate crc;
 extern crate dotenv;
 extern crate futures;
+extern crate h2;
+extern crate http;
 extern crate hyper;
 #[macro_use]
 extern crate language_tags;
@@ -12,39 +16,68 @@ extern crate ring;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate tokio_core as tokio;
+extern crate tokio_io;
+extern crate tokio_signal;
 extern crate tokio_tls;
+#[cfg(unix)]
+extern crate tokio_uds;
+extern crate toml;
 extern crate unicase;
 extern crate url;
 extern crate uuid;
+mod admin;
 mod auth;
+mod config;
 mod flow;
+mod health;
+mod http2;
+mod limits;
+mod listener;
+mod logger;
+mod metrics;
 mod pool;
+mod proxy;
 mod tls;
 mod utils;
 
-use auth::{Authorizer, HMACAuthorizer};
+use auth::{Authorizer, CertAuthorizer, HMACAuthorizer};
+use bytes::Bytes;
+use config::Config;
 use dotenv::dotenv;
-use flow::{Error as FlowError, Flow};
-use futures::{future, stream, Future, Sink, Stream, Then};
-use hyper::{Error as HyperError, Method, StatusCode,
+use flow::{Chunk, Error as FlowError, Flow};
+use futures::{future, stream, Future, Sink, Stream, Then, future::Either};
+use health::Health;
+use hyper::{Error as HyperError, Method, StatusCode, mime::Mime,
             header::{AcceptRanges, AccessControlAllowHeaders, AccessControlAllowMethods,
                      AccessControlAllowOrigin, AccessControlRequestHeaders, ByteRangeSpec,
                      CacheControl, CacheDirective, Charset, ContentDisposition, ContentLength,
                      ContentRange, ContentRangeSpec, ContentType, DispositionParam,
                      DispositionType, ETag, EntityTag, Range, RangeUnit}};
 use hyper::server::{Http, Request, Response, Service};
-use native_tls::TlsAcceptor;
-use pool::Pool;
+use limits::{Activity, ClientWait, Limits, Monitored, TokenBucket, Violation};
+use listener::{AsyncStream, ListenAddr, Listener, Stream as ListenerStream};
+use logger::{Level, Logger};
+use metrics::{Metrics, Route};
+use pool::{Pool, SharedFlow};
 use regex::Regex;
 use serde::de::DeserializeOwned;
-use std::{error, fmt, io::{self, Error as IoError}, marker::PhantomData, sync::{Arc, RwLock},
-          time::Duration, {env, mem, thread}};
-use tokio::reactor::{self, Core};
-use tokio_tls::TlsAcceptorExt;
+use std::{error, fmt, cell::Cell, collections::BTreeMap, io::{self, Error as IoError},
+          marker::PhantomData, rc::Rc,
+          sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::{Duration, Instant},
+          {cmp, env, fs, mem, process, thread}};
+use tokio::reactor::{self, Core, Interval};
+use tls::{SharedAcceptor, TlsSource};
+use tokio_io::{AsyncRead, AsyncWrite};
 use utils::BoxedFuture;
 
+/// The most bytes of filename and metadata a flow may carry.
+const MAX_METADATA_SIZE: usize = 4096;
+/// The most chunks listed at once.
+const MAX_CHUNK_LISTING: usize = 1000;
+
 #[derive(Debug)]
 pub enum Error {
     Invalid,
@@ -69,18 +102,37 @@ impl error::Error for Error {
 }
 
 struct FlowService<ProtoReq, ProtoRes, ProtoErr> {
-    pool: Arc<RwLock<Pool>>,
+    pool: Arc<Pool>,
     remote: reactor::Remote,
     meta_capacity: u64,
     data_capacity: u64,
+    chunk_size: u64,
+    max_chunk_size: u64,
+    max_ttl: Option<u64>,
+    max_idle_timeout: Option<u64>,
     authorizer: Arc<Authorizer>,
+    client_subject: Option<String>,
+    metrics: Arc<Metrics>,
+    logger: Arc<Logger>,
+    health: Arc<Health>,
+    activity: Arc<Activity>,
+    client_bucket: Option<Arc<TokenBucket>>,
     _marker: PhantomData<(ProtoReq, ProtoRes, ProtoErr)>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 struct NewRequest {
     pub size: Option<u64>,
     pub preserve_mode: bool,
+    pub chunk_size: Option<u64>,
+    pub ttl: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub push_rate: Option<u64>,
+    pub pull_rate: Option<u64>,
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+    pub metadata: Option<BTreeMap<String, String>>,
+    pub sha256: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -89,12 +141,22 @@ struct NewResponse {
     pub token: String,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Default, Serialize, Deserialize, PartialEq, Debug)]
 struct StatusResponse {
     pub tail: u64,
     pub next: u64,
     pub dropped: u64,
     pub pushed: u64,
+    pub lifetime: Option<u64>,
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+    pub metadata: Option<BTreeMap<String, String>>,
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ChunksResponse {
+    pub chunks: Vec<flow::ChunkInfo>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -106,24 +168,62 @@ type ResponseFuture = Box<Future<Item = Response, Error = HyperError> + Send>;
 
 impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
     fn new(
-        pool: Arc<RwLock<Pool>>,
+        pool: Arc<Pool>,
         remote: reactor::Remote,
         meta_capacity: u64,
         data_capacity: u64,
+        chunk_size: u64,
+        max_chunk_size: u64,
+        max_ttl: Option<u64>,
+        max_idle_timeout: Option<u64>,
         authorizer: Arc<Authorizer>,
+        metrics: Arc<Metrics>,
+        logger: Arc<Logger>,
+        health: Arc<Health>,
+        activity: Arc<Activity>,
+        client_bucket: Option<Arc<TokenBucket>>,
     ) -> Self {
         FlowService {
             pool,
             remote,
             meta_capacity,
             data_capacity,
+            chunk_size,
+            max_chunk_size,
+            max_ttl,
+            max_idle_timeout,
             authorizer,
+            client_subject: None,
+            metrics,
+            logger,
+            health,
+            activity,
+            client_bucket,
             _marker: PhantomData,
         }
     }
 
-    fn check_authorization(&self, flow_id: &str, token: &str) -> bool {
-        self.authorizer.verify(flow_id, token).is_ok()
+    fn with_client_subject(mut self, client_subject: Option<String>) -> Self {
+        self.client_subject = client_subject;
+        self
+    }
+
+    fn check_authorization(&self, flow_id: &str, req: &Request) -> Result<(), Response> {
+        if let Some(ref subject) = self.client_subject {
+            let owner = self.pool
+                .get(flow_id)
+                .and_then(|flow_ptr| flow_ptr.read().unwrap().get_config().owner.clone());
+            let owner = owner.as_ref().map(|owner| owner.as_str());
+            if self.authorizer.verify_subject(owner, subject).is_ok() {
+                return Ok(());
+            }
+        }
+        match Self::parse_request_querystring(req).find(|&(ref key, _)| key == "token") {
+            Some((_, token)) => self.authorizer
+                .verify(flow_id, &token)
+                .map_err(|_| Response::new().with_status(StatusCode::NotFound)),
+            None => Err(Self::response_error("Missing Token")),
+        }
     }
 
     fn parse_request_querystring(req: &Request) -> url::form_urlencoded::Parse {
@@ -148,10 +248,78 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
             .boxed2()
     }
 
+    fn bound_timeout(
+        timeout: Option<u64>,
+        max_timeout: Option<u64>,
+    ) -> Result<Option<u64>, Error> {
+        match (timeout, max_timeout) {
+            (Some(0), _) => Err(Error::Invalid),
+            (Some(timeout), Some(max_timeout)) if timeout > max_timeout => Err(Error::Invalid),
+            (timeout, max_timeout) => Ok(timeout.or(max_timeout)),
+        }
+    }
+
+    /// Normalize a SHA-256 given in hex to lowercase.
+    fn parse_digest(sha256: &str) -> Result<String, Error> {
+        let sha256 = sha256.to_lowercase();
+        match utils::unhex(&sha256) {
+            Ok(ref bytes) if bytes.len() == 32 => Ok(sha256),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// The `Digest` header value of a SHA-256 in hex.
+    fn digest_header(sha256: &str) -> String {
+        format!("sha-256={}", base64::encode(&utils::unhex(sha256).unwrap()))
+    }
+
+    fn is_form_type(content_type: &Mime) -> bool {
+        let type_ = content_type.type_().as_str().to_lowercase();
+        let subtype = content_type.subtype().as_str().to_lowercase();
+        match (type_.as_str(), subtype.as_str()) {
+            ("application", "x-www-form-urlencoded") | ("multipart", _) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether browsers may show the content type inline. Anything else, such as HTML or SVG,
+    /// could run scripts on this origin, so it is only served as an attachment.
+    fn is_inline_type(content_type: &Mime) -> bool {
+        let type_ = content_type.type_().as_str().to_lowercase();
+        let subtype = content_type.subtype().as_str().to_lowercase();
+        match (type_.as_str(), subtype.as_str()) {
+            ("text", "plain") | ("application", "octet-stream") | ("application", "json") => true,
+            ("image", "png") | ("image", "jpeg") | ("image", "gif") | ("image", "webp") => true,
+            ("audio", _) | ("video", _) => true,
+            _ => false,
+        }
+    }
+
+    /// The buckets a transfer is charged to: the flow's own, and the one of its client.
+    fn rate_buckets(
+        flow_bucket: Option<Arc<TokenBucket>>,
+        client_bucket: &Option<Arc<TokenBucket>>,
+    ) -> Vec<Arc<TokenBucket>> {
+        flow_bucket.into_iter().chain(client_bucket.clone()).collect()
+    }
+
     fn response_ok() -> Response {
         Response::new().with_header(ContentLength(0))
     }
 
+    fn response_json<T: serde::Serialize>(data: &T, ok: bool) -> Response {
+        let body = serde_json::to_string(data).unwrap();
+        Response::new()
+            .with_status(if ok {
+                StatusCode::Ok
+            } else {
+                StatusCode::ServiceUnavailable
+            })
+            .with_header(ContentType::json())
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body)
+    }
+
     fn response_error(error: &str) -> Response {
         let body = serde_json::to_string(&ErrorResponse {
             message: error.to_owned(),
@@ -163,26 +331,94 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
     }
 
     fn handle_new(&self, req: Request, _route: regex::Captures) -> ResponseFuture {
+        if self.health.is_draining() {
+            return future::ok(Response::new().with_status(StatusCode::ServiceUnavailable))
+                .boxed2();
+        }
+        let client_subject = self.client_subject.as_ref().map(|subject| subject.as_str());
+        if self.authorizer.verify_new(client_subject).is_err() {
+            return future::ok(Response::new().with_status(StatusCode::Forbidden)).boxed2();
+        }
+        let owner = self.client_subject.clone();
         let pool_ptr = self.pool.clone();
         let meta_capacity = self.meta_capacity;
         let data_capacity = self.data_capacity;
+        let default_chunk_size = self.chunk_size;
+        let max_chunk_size = self.max_chunk_size;
+        let max_ttl = self.max_ttl;
+        let max_idle_timeout = self.max_idle_timeout;
         let authorizer = self.authorizer.clone();
+        let metrics = self.metrics.clone();
+        let logger = self.logger.clone();
         Self::parse_request_parameter::<NewRequest>(req)
             .and_then(move |param| {
+                let chunk_size = param.chunk_size.unwrap_or(default_chunk_size);
+                if chunk_size < flow::MIN_CHUNK_SIZE as u64 || chunk_size > max_chunk_size {
+                    return Err(Error::Invalid);
+                }
+                let ttl = Self::bound_timeout(param.ttl, max_ttl)?;
+                let idle_timeout = Self::bound_timeout(param.idle_timeout, max_idle_timeout)?;
+                if param.push_rate == Some(0) || param.pull_rate == Some(0) {
+                    return Err(Error::Invalid);
+                }
+                if let Some(ref content_type) = param.content_type {
+                    content_type.parse::<Mime>().map_err(|_| Error::Invalid)?;
+                }
+                let metadata_size = param.filename.as_ref().map_or(0, |filename| filename.len())
+                    + param.metadata.as_ref().map_or(0, |metadata| {
+                        metadata.iter().map(|(key, value)| key.len() + value.len()).sum()
+                    });
+                if metadata_size > MAX_METADATA_SIZE {
+                    return Err(Error::Invalid);
+                }
+                let sha256 = match param.sha256 {
+                    Some(ref sha256) => Some(Self::parse_digest(sha256)?),
+                    None => None,
+                };
                 let flow_ptr = Flow::new(flow::Config {
                     length: param.size,
                     meta_capacity,
                     data_capacity,
                     keepcount: Some(1),
                     preserve_mode: param.preserve_mode,
+                    chunk_size,
+                    ttl,
+                    idle_timeout,
+                    push_rate: param.push_rate,
+                    pull_rate: param.pull_rate,
+                    content_type: param.content_type.clone(),
+                    filename: param.filename.clone(),
+                    metadata: param.metadata.clone(),
+                    sha256,
+                    owner,
                 });
-                let flow_id = flow_ptr.read().unwrap().id.to_owned();
-                {
-                    let mut pool = pool_ptr.write().unwrap();
-                    pool.insert(flow_ptr)
-                        .map(|_| flow_id.clone())
-                        .map_err(|_| Error::NotReady)
-                }
+                let flow_id = {
+                    let mut flow = flow_ptr.write().unwrap();
+                    flow.observe(metrics.clone());
+                    flow.observe(logger.clone());
+                    flow.id.to_owned()
+                };
+                pool_ptr
+                    .insert(flow_ptr)
+                    .map(|_| {
+                        metrics.add_created();
+                        logger.log(
+                            Level::Info,
+                            "flow_created",
+                            json!({
+                                "flow_id": flow_id,
+                                "size": param.size,
+                                "preserve_mode": param.preserve_mode,
+                                "chunk_size": chunk_size,
+                                "ttl": ttl,
+                                "idle_timeout": idle_timeout,
+                                "push_rate": param.push_rate,
+                                "pull_rate": param.pull_rate,
+                            }),
+                        );
+                        flow_id.clone()
+                    })
+                    .map_err(|_| Error::NotReady)
             })
             .and_then(move |flow_id: String| {
                 let token = authorizer.sign(&flow_id);
@@ -205,37 +441,61 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
     }
 
     fn handle_push(&self, req: Request, route: regex::Captures) -> ResponseFuture {
-        let token = match Self::parse_request_querystring(&req).find(|&(ref key, _)| key == "token")
-        {
-            Some((_, token)) => token.into_owned(),
-            None => return future::ok(Self::response_error("Missing Token")).boxed2(),
-        };
         let flow_id = route.get(1).unwrap().as_str();
-        if !self.check_authorization(flow_id, &token) {
-            return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2();
+        if let Err(response) = self.check_authorization(flow_id, &req) {
+            return future::ok(response).boxed2();
         }
-        let flow_ptr = match self.pool.read().unwrap().get(flow_id) {
+        let flow_ptr = match self.pool.get(flow_id) {
             Some(flow) => flow.clone(),
             None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
         };
-        req.body()
-            .fold(Vec::<u8>::with_capacity(flow::REF_SIZE * 2), {
+        // Form submissions carry the type of the form rather than of the data.
+        let content_type = match req.headers().get::<ContentType>() {
+            Some(&ContentType(ref content_type)) if !Self::is_form_type(content_type) => {
+                Some(content_type.to_string())
+            }
+            _ => None,
+        };
+        let (chunk_size, buckets) = {
+            let flow = flow_ptr.read().unwrap();
+            let buckets = Self::rate_buckets(flow.get_push_bucket(), &self.client_bucket);
+            (flow.get_config().chunk_size as usize, buckets)
+        };
+        let remote = self.remote.clone();
+        let transfer = Health::start_transfer(&self.health);
+        ClientWait::upstream(req.body(), self.activity.clone(), |chunk| chunk.len())
+            .fold(Vec::<u8>::with_capacity(chunk_size * 2), {
                 let flow_ptr = flow_ptr.clone();
+                let content_type = content_type.clone();
                 move |mut buf_chunk, chunk| {
+                    // Hold back the next read until the rate limits allow this one.
+                    let wait = limits::throttle(&remote, &buckets, chunk.len() as u64);
                     buf_chunk.extend_from_slice(&chunk);
-                    if buf_chunk.len() >= flow::REF_SIZE {
-                        let chunk = mem::replace(
-                            &mut buf_chunk,
-                            Vec::<u8>::with_capacity(flow::REF_SIZE * 2),
-                        );
-                        let mut flow = flow_ptr.write().unwrap();
-                        flow.push(chunk)
+                    // Cut the buffer into chunks of the target size. They are checksummed here,
+                    // without holding the flow lock.
+                    let mut chunks = Vec::new();
+                    while buf_chunk.len() >= chunk_size {
+                        let remain = buf_chunk.split_off(chunk_size);
+                        chunks.push(Chunk::data(mem::replace(&mut buf_chunk, remain)));
+                    }
+                    let push_fut = if chunks.len() > 0 {
+                        let flow_ptr = flow_ptr.clone();
+                        let content_type = content_type.clone();
+                        stream::iter_ok(chunks)
+                            .for_each(move |chunk| {
+                                let mut flow = flow_ptr.write().unwrap();
+                                if let Some(ref content_type) = content_type {
+                                    flow.set_content_type(content_type.clone());
+                                }
+                                flow.push(chunk).map(|_| ())
+                            })
                             .map(|_| buf_chunk)
                             .map_err(|err| HyperError::Io(IoError::new(io::ErrorKind::Other, err)))
                             .boxed2()
                     } else {
                         future::ok(buf_chunk).boxed2()
-                    }
+                    };
+                    wait.then(move |_| push_fut).boxed2()
                 }
             })
             .and_then({
@@ -243,7 +503,11 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
                 move |chunk| {
                     // Flush remaining chunk.
                     if chunk.len() > 0 {
+                        let chunk = Chunk::data(chunk);
                         let mut flow = flow_ptr.write().unwrap();
+                        if let Some(content_type) = content_type {
+                            flow.set_content_type(content_type);
+                        }
                         flow.push(chunk)
                             .map(|_| ())
                             .map_err(|err| HyperError::Io(IoError::new(io::ErrorKind::Other, err)))
@@ -253,8 +517,19 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
                     }
                 }
             })
+            .then(move |result| {
+                drop(transfer);
+                result
+            })
             .then(move |result| match result {
                 Ok(_) => future::ok(Self::response_ok()).boxed2(),
+                Err(HyperError::Io(ref err))
+                    if err.get_ref()
+                        .and_then(|inner| inner.downcast_ref::<FlowError>())
+                        == Some(&FlowError::Mismatch) =>
+                {
+                    future::ok(Self::response_error("Digest Mismatch")).boxed2()
+                }
                 Err(HyperError::Io(ref err))
                     if err.get_ref()
                         .and_then(|inner| inner.downcast_ref::<FlowError>())
@@ -274,25 +549,33 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
     }
 
     fn handle_eof(&self, req: Request, route: regex::Captures) -> ResponseFuture {
-        let token = match Self::parse_request_querystring(&req).find(|&(ref key, _)| key == "token")
-        {
-            Some((_, token)) => token.into_owned(),
-            None => return future::ok(Self::response_error("Missing Token")).boxed2(),
-        };
         let flow_id = route.get(1).unwrap().as_str();
-        if !self.check_authorization(flow_id, &token) {
-            return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2();
+        if let Err(response) = self.check_authorization(flow_id, &req) {
+            return future::ok(response).boxed2();
         }
-        let flow_ptr = match self.pool.read().unwrap().get(flow_id) {
+        let flow_ptr = match self.pool.get(flow_id) {
             Some(flow) => flow.clone(),
             None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
         };
+        let opt_sha256 = match Self::parse_request_querystring(&req)
+            .find(|&(ref key, _)| key == "sha256")
+            .map(|(_, sha256)| Self::parse_digest(&sha256))
+        {
+            Some(Ok(sha256)) => Some(sha256),
+            Some(Err(_)) => return future::ok(Self::response_error("Invalid Parameter")).boxed2(),
+            None => None,
+        };
         {
             let mut flow = flow_ptr.write().unwrap();
-            flow.close()
+            let close_fut = match opt_sha256 {
+                Some(sha256) => flow.close_with_digest(&sha256),
+                None => flow.close(),
+            };
+            close_fut
                 .then(|result| match result {
                     Ok(_) => Ok(Self::response_ok()),
                     Err(FlowError::Invalid) => Ok(Self::response_error("Closed")),
+                    Err(FlowError::Mismatch) => Ok(Self::response_error("Digest Mismatch")),
                     _ => Ok(Response::new().with_status(StatusCode::InternalServerError)),
                 })
                 .boxed2()
@@ -301,7 +584,7 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
 
     fn handle_status(&self, _req: Request, route: regex::Captures) -> ResponseFuture {
         let flow_id = route.get(1).unwrap().as_str();
-        let flow_ptr = match self.pool.read().unwrap().get(flow_id) {
+        let flow_ptr = match self.pool.get(flow_id) {
             Some(flow) => flow.clone(),
             None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
         };
@@ -309,11 +592,21 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
             let flow = flow_ptr.read().unwrap();
             let (tail, next) = flow.get_range();
             let statistic = flow.get_statistic();
+            let config = flow.get_config();
+            // Round up the remaining lifetime to seconds.
+            let lifetime = flow.get_lifetime().map(|lifetime| {
+                lifetime.as_secs() + if lifetime.subsec_nanos() > 0 { 1 } else { 0 }
+            });
             serde_json::to_string(&StatusResponse {
                 tail,
                 next,
                 dropped: statistic.dropped,
                 pushed: statistic.pushed,
+                lifetime,
+                content_type: config.content_type.clone(),
+                filename: config.filename.clone(),
+                metadata: config.metadata.clone(),
+                sha256: flow.get_digest(),
             }).unwrap()
         }.into_bytes();
         future::ok(
@@ -330,24 +623,31 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
             Ok(index) => index,
             Err(_) => return future::ok(Self::response_error("Invalid Parameter")).boxed2(),
         };
-        let flow_ptr = match self.pool.read().unwrap().get(flow_id) {
+        let flow_ptr = match self.pool.get(flow_id) {
             Some(flow) => flow.clone(),
             None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
         };
+        let metrics = self.metrics.clone();
         {
             let flow = flow_ptr.read().unwrap();
-            flow.pull(chunk_index, None)
-                .and_then(|chunk| {
-                    future::ok(
-                        Response::new()
-                            .with_header(ContentType::octet_stream())
-                            .with_header(ContentLength(chunk.len() as u64))
-                            .with_header(CacheControl(vec![
-                                CacheDirective::MaxAge(365000000),
-                                CacheDirective::Extension("immutable".into(), None),
-                            ]))
-                            .with_body(chunk),
-                    )
+            flow.pull_chunk(chunk_index, None)
+                .and_then(move |(chunk, checksum)| {
+                    metrics.add_pulled(chunk.len() as u64);
+                    let mut response = Response::new()
+                        .with_header(ContentType::octet_stream())
+                        .with_header(ContentLength(chunk.len() as u64))
+                        .with_header(CacheControl(vec![
+                            CacheDirective::MaxAge(365000000),
+                            CacheDirective::Extension("immutable".into(), None),
+                        ]))
+                        .with_body(chunk);
+                    response
+                        .headers_mut()
+                        .set_raw("Digest", Self::digest_header(&checksum.sha256));
+                    response
+                        .headers_mut()
+                        .set_raw("X-Checksum-CRC32C", checksum.crc32c);
+                    future::ok(response)
                 })
                 .or_else(|err| {
                     let status = match err {
@@ -360,35 +660,94 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
         }
     }
 
-    fn handle_pull(&self, req: Request, route: regex::Captures) -> ResponseFuture {
+    fn handle_chunks(&self, req: Request, route: regex::Captures) -> ResponseFuture {
+        let mut from = 0;
+        let mut limit = MAX_CHUNK_LISTING;
+        for (key, value) in Self::parse_request_querystring(&req) {
+            let valid = match key.as_ref() {
+                "from" => value.parse().map(|value| from = value).is_ok(),
+                "limit" => value.parse().map(|value| limit = value).is_ok(),
+                _ => true,
+            };
+            if !valid {
+                return future::ok(Self::response_error("Invalid Parameter")).boxed2();
+            }
+        }
+        if limit == 0 || limit > MAX_CHUNK_LISTING {
+            return future::ok(Self::response_error("Invalid Parameter")).boxed2();
+        }
+        let flow_id = route.get(1).unwrap().as_str();
+        let flow_ptr = match self.pool.get(flow_id) {
+            Some(flow) => flow.clone(),
+            None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
+        };
+        let body = {
+            let flow = flow_ptr.read().unwrap();
+            serde_json::to_string(&ChunksResponse {
+                chunks: flow.get_chunks(from, limit),
+            }).unwrap()
+        }.into_bytes();
+        future::ok(
+            Response::new()
+                .with_header(ContentType::json())
+                .with_header(ContentLength(body.len() as u64))
+                .with_header(CacheControl(vec![CacheDirective::NoCache]))
+                .with_body(body),
+        ).boxed2()
+    }
+
+    fn handle_pull(
+        &self,
+        req: Request,
+        route: regex::Captures,
+        body_done: futures::sync::oneshot::Sender<u64>,
+    ) -> ResponseFuture {
         let opt_filename = Self::parse_request_querystring(&req)
             .find(|&(ref key, _)| key == "filename")
             .map(|(_, token)| token.into_owned());
         let flow_id = route.get(1).unwrap().as_str();
-        let flow_ptr = match self.pool.read().unwrap().get(flow_id) {
+        let flow_ptr = match self.pool.get(flow_id) {
             Some(flow) => flow.clone(),
             None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
         };
-        let (tx, body) = hyper::Body::pair();
+        // The producer's content type and filename are the defaults.
+        let (content_type, opt_filename) = {
+            let flow = flow_ptr.read().unwrap();
+            let config = flow.get_config();
+            let content_type = config
+                .content_type
+                .as_ref()
+                .and_then(|content_type| content_type.parse().ok())
+                .map(ContentType)
+                .unwrap_or_else(ContentType::octet_stream);
+            (content_type, opt_filename.or_else(|| config.filename.clone()))
+        };
+        let is_inline = Self::is_inline_type(&content_type.0);
         let mut response = Response::new()
-            .with_header(ContentType::octet_stream())
+            .with_header(content_type)
             .with_header(CacheControl(vec![CacheDirective::NoCache]))
-            .with_header(ETag(EntityTag::new(false, flow_id.to_owned())))
-            .with_body(body);
-        if let Some(filename) = opt_filename {
+            .with_header(ETag(EntityTag::new(false, flow_id.to_owned())));
+        // Browsers must not guess a more dangerous type from the data.
+        response
+            .headers_mut()
+            .set_raw("X-Content-Type-Options", "nosniff");
+        if opt_filename.is_some() || !is_inline {
             let content_disp = ContentDisposition {
                 disposition: DispositionType::Attachment,
-                parameters: vec![
-                    DispositionParam::Filename(
-                        Charset::Ext("UTF-8".into()),
-                        Some(langtag!(en)),
-                        filename.as_bytes().to_vec(),
-                    ),
-                ],
+                parameters: opt_filename
+                    .map(|filename| {
+                        DispositionParam::Filename(
+                            Charset::Ext("UTF-8".into()),
+                            Some(langtag!(en)),
+                            filename.as_bytes().to_vec(),
+                        )
+                    })
+                    .into_iter()
+                    .collect(),
             };
             response.headers_mut().set(content_disp);
         }
-        let (pull_fut, mut chunk_index, mut skip_len) = {
+        let (pull_fut, chunk_index, skip_len, buckets) = {
             let flow = flow_ptr.read().unwrap();
             let (tail_index, _) = flow.get_range();
             let config = flow.get_config();
@@ -441,43 +800,219 @@ impl<ProtoReq, ProtoRes, ProtoErr> FlowService<ProtoReq, ProtoRes, ProtoErr> {
                         .headers_mut()
                         .set(AcceptRanges(vec![RangeUnit::Bytes]));
                     response.headers_mut().set(ContentLength(length));
+                    // The digest of the whole content, once the data is known to match it.
+                    if let Some(sha256) = flow.get_digest() {
+                        response
+                            .headers_mut()
+                            .set_raw("Digest", Self::digest_header(&sha256));
+                    }
+                }
+            }
+            let buckets = Self::rate_buckets(flow.get_pull_bucket(), &self.client_bucket);
+            (flow.pull(tail_index, None), tail_index, skip_len, buckets)
+        };
+        self.stream_chunks(
+            flow_ptr,
+            response,
+            pull_fut,
+            chunk_index,
+            skip_len,
+            None,
+            buckets,
+            body_done,
+        )
+    }
+
+    fn handle_read(
+        &self,
+        req: Request,
+        route: regex::Captures,
+        body_done: futures::sync::oneshot::Sender<u64>,
+    ) -> ResponseFuture {
+        let mut opt_offset = None;
+        let mut opt_length = None;
+        for (key, value) in Self::parse_request_querystring(&req) {
+            let valid = match key.as_ref() {
+                "offset" => value.parse().map(|value| opt_offset = Some(value)).is_ok(),
+                "length" => value.parse().map(|value| opt_length = Some(value)).is_ok(),
+                _ => true,
+            };
+            if !valid {
+                return future::ok(Self::response_error("Invalid Parameter")).boxed2();
+            }
+        }
+        let offset: u64 = match opt_offset {
+            Some(offset) => offset,
+            None => return future::ok(Self::response_error("Invalid Parameter")).boxed2(),
+        };
+        match opt_length {
+            Some(length) if length == 0 || offset.checked_add(length).is_none() => {
+                return future::ok(Self::response_error("Invalid Parameter")).boxed2();
+            }
+            _ => (),
+        }
+        let flow_id = route.get(1).unwrap().as_str();
+        let flow_ptr = match self.pool.get(flow_id) {
+            Some(flow) => flow.clone(),
+            None => return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
+        };
+        let mut response = Response::new()
+            .with_header(ContentType::octet_stream())
+            .with_header(CacheControl(vec![CacheDirective::NoCache]));
+        let (pull_fut, chunk_index, skip_len, limit, buckets) = {
+            let flow = flow_ptr.read().unwrap();
+            let pushed = flow.get_statistic().pushed;
+            // The total length is known once fixed or closed.
+            let opt_total = match (flow.get_config().length, flow.get_state()) {
+                (Some(length), _) => Some(length),
+                (None, &flow::State::Streaming) => None,
+                (None, _) => Some(pushed),
+            };
+            if let Some(total) = opt_total {
+                if offset >= total {
+                    return future::ok(
+                        Response::new()
+                            .with_status(StatusCode::RangeNotSatisfiable)
+                            .with_header(ContentRange(ContentRangeSpec::Bytes {
+                                range: None,
+                                instance_length: Some(total),
+                            })),
+                    ).boxed2();
+                }
+            }
+            let (chunk_index, skip_len) = match flow.locate(offset) {
+                Ok(position) => position,
+                Err(_) => {
+                    return future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2()
                 }
+            };
+            let limit = match (opt_length, opt_total) {
+                (Some(length), Some(total)) => Some(length.min(total - offset)),
+                (opt_length, _) => opt_length,
+            };
+            // Set content length only when the whole range is sure to come.
+            let opt_content_length = match (limit, opt_total) {
+                (Some(limit), _) if offset + limit <= pushed => Some(limit),
+                (limit, Some(total)) => Some(limit.unwrap_or(total - offset)),
+                _ => None,
+            };
+            if let Some(content_length) = opt_content_length {
+                response.headers_mut().set(ContentLength(content_length));
             }
-            (flow.pull(tail_index, None), tail_index, skip_len)
+            let buckets = Self::rate_buckets(flow.get_pull_bucket(), &self.client_bucket);
+            (
+                flow.pull(chunk_index, None),
+                chunk_index,
+                skip_len,
+                limit,
+                buckets,
+            )
         };
+        self.stream_chunks(
+            flow_ptr,
+            response,
+            pull_fut,
+            chunk_index,
+            skip_len,
+            limit,
+            buckets,
+            body_done,
+        )
+    }
+
+    /// Send the chunks from `chunk_index` as the body of the response, skipping the first
+    /// `skip_len` bytes and stopping after `limit` bytes if given. The number of bytes sent is
+    /// given to `body_done` once the body is finished.
+    fn stream_chunks(
+        &self,
+        flow_ptr: SharedFlow,
+        response: Response,
+        pull_fut: Box<Future<Item = Bytes, Error = FlowError> + Send>,
+        mut chunk_index: u64,
+        mut skip_len: u64,
+        mut limit: Option<u64>,
+        buckets: Vec<Arc<TokenBucket>>,
+        body_done: futures::sync::oneshot::Sender<u64>,
+    ) -> ResponseFuture {
+        let (tx, body) = hyper::Body::pair();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let body_sent = sent.clone();
+        let response = response.with_body(body);
         let remote = self.remote.clone();
+        let throttle_remote = self.remote.clone();
+        let metrics = self.metrics.clone();
+        let transfer = Health::start_transfer(&self.health);
+        // The request lasts until the body is sent, not just the response head.
+        let request = Activity::start_request(&self.activity);
+        let activity = self.activity.clone();
         pull_fut
             .and_then(move |chunk| {
-                let body_stream = stream::unfold(Some(chunk), move |previous| {
-                    // Check if the flow is EOF.
-                    if let Some(prev_chunk) = previous {
+                let body_stream = stream::unfold(Some(Ok(chunk)), move |previous| match previous {
+                    Some(Ok(prev_chunk)) => {
                         let flow = flow_ptr.read().unwrap();
                         let prev_chunk_len = prev_chunk.len() as u64;
-                        let hyper_chunk: Result<hyper::Chunk, _> = if skip_len == 0 {
-                            Ok(prev_chunk.into())
+                        let mut data = if skip_len == 0 {
+                            prev_chunk
                         } else if prev_chunk_len <= skip_len {
                             skip_len -= prev_chunk_len;
-                            Ok(vec![].into())
+                            Bytes::new()
                         } else {
                             let slice_chunk = prev_chunk.slice_from(skip_len as usize);
                             skip_len = 0;
-                            Ok(slice_chunk.into())
+                            slice_chunk
                         };
+                        // Cut the chunk at the end of the range.
+                        if let Some(remain) = limit {
+                            if data.len() as u64 > remain {
+                                data.truncate(remain as usize);
+                            }
+                            limit = Some(remain - data.len() as u64);
+                        }
+                        let hyper_chunk: Result<hyper::Chunk, _> = Ok(data.into());
+                        if let Ok(ref hyper_chunk) = hyper_chunk {
+                            metrics.add_pulled(hyper_chunk.len() as u64);
+                            sent.fetch_add(hyper_chunk.len(), Ordering::Relaxed);
+                        }
+                        // Hold back the chunk until the rate limits allow it.
+                        let len = hyper_chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                        let wait = limits::throttle(&throttle_remote, &buckets, len);
                         chunk_index += 1;
-                        let fut = flow.pull(chunk_index, None).then(move |ret| match ret {
-                            Ok(chunk) => future::ok((hyper_chunk, Some(chunk))),
-                            Err(_) => future::ok((hyper_chunk, None)),
-                        });
-                        Some(fut)
-                    } else {
-                        None
+                        let fut = if limit == Some(0) {
+                            // The range is complete.
+                            future::ok((hyper_chunk, None)).boxed2()
+                        } else {
+                            flow.pull(chunk_index, None)
+                                .then(move |ret| match ret {
+                                    Ok(chunk) => future::ok((hyper_chunk, Some(Ok(chunk)))),
+                                    Err(FlowError::Eof) => future::ok((hyper_chunk, None)),
+                                    Err(err) => future::ok((hyper_chunk, Some(Err(err)))),
+                                })
+                                .boxed2()
+                        };
+                        Some(wait.then(move |_| fut).boxed2())
                     }
+                    // Abort the body so an unfinished flow doesn't look complete.
+                    Some(Err(_)) => Some(future::ok((Err(HyperError::Incomplete), None)).boxed2()),
+                    // The flow is EOF.
+                    None => None,
                 });
                 // Schedule the sender to the reactor.
+                let body_stream =
+                    ClientWait::downstream(body_stream, activity, |chunk| match *chunk {
+                        Ok(ref chunk) => chunk.len(),
+                        Err(_) => 0,
+                    });
                 remote.spawn(move |_| {
                     tx.send_all(body_stream)
                         .and_then(|(mut tx, _)| tx.close())
-                        .then(|_| Ok(()))
+                        .then(move |_| {
+                            drop(transfer);
+                            drop(request);
+                            body_done
+                                .send(body_sent.load(Ordering::Relaxed) as u64)
+                                .is_ok();
+                            Ok(())
+                        })
                 });
                 Ok(response)
             })
@@ -511,32 +1046,73 @@ where
             static ref PATTERN_FETCH: Regex =
                 Regex::new(r"^/flow/([a-f0-9]{32})/fetch/(\d+)$").unwrap();
             static ref PATTERN_PULL: Regex = Regex::new(r"^/flow/([a-f0-9]{32})/pull$").unwrap();
+            static ref PATTERN_CHUNKS: Regex =
+                Regex::new(r"^/flow/([a-f0-9]{32})/chunks$").unwrap();
+            static ref PATTERN_READ: Regex = Regex::new(r"^/flow/([a-f0-9]{32})/read$").unwrap();
+            static ref PATTERN_FLOW_ID: Regex = Regex::new(r"^/flow/([a-f0-9]{32})/").unwrap();
+            static ref PATTERN_HEALTHZ: Regex = Regex::new(r"^/healthz$").unwrap();
+            static ref PATTERN_READYZ: Regex = Regex::new(r"^/readyz$").unwrap();
         }
         let req = Request::from(req);
+        let request = Activity::start_request(&self.activity);
         let path = &req.path().to_owned();
-        match req.method() {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let flow_id = PATTERN_FLOW_ID
+            .captures(path)
+            .map(|route| route.get(1).unwrap().as_str().to_owned());
+        let bytes_in = req.headers().get::<ContentLength>().map(|len| **len);
+        // Set when the response body is streamed after the response head.
+        let mut opt_body_done = None;
+        let (route, fut) = match req.method() {
             &Method::Post => if let Some(route) = PATTERN_NEW.captures(path) {
-                self.handle_new(req, route)
+                (Some(Route::New), self.handle_new(req, route))
             } else if let Some(route) = PATTERN_PUSH.captures(path) {
-                self.handle_push(req, route)
+                (Some(Route::Push), self.handle_push(req, route))
             } else if let Some(route) = PATTERN_EOF.captures(path) {
-                self.handle_eof(req, route)
+                (Some(Route::Eof), self.handle_eof(req, route))
             } else if let Some(route) = PATTERN_STATUS.captures(path) {
-                self.handle_status(req, route)
+                (Some(Route::Status), self.handle_status(req, route))
             } else {
-                future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2()
+                (
+                    Some(Route::Unknown),
+                    future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
+                )
             },
             &Method::Put => if let Some(route) = PATTERN_PUSH.captures(path) {
-                self.handle_push(req, route)
+                (Some(Route::Push), self.handle_push(req, route))
             } else {
-                future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2()
+                (
+                    Some(Route::Unknown),
+                    future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
+                )
             },
-            &Method::Get => if let Some(route) = PATTERN_FETCH.captures(path) {
-                self.handle_fetch(req, route)
+            &Method::Get => if PATTERN_HEALTHZ.is_match(path) {
+                // Probes bypass the access log and request metrics.
+                let health = self.health.check_alive();
+                let alive = health.alive;
+                (None, future::ok(Self::response_json(&health, alive)).boxed2())
+            } else if PATTERN_READYZ.is_match(path) {
+                let ready = self.health.check_ready(&self.pool);
+                let is_ready = ready.ready;
+                (None, future::ok(Self::response_json(&ready, is_ready)).boxed2())
+            } else if let Some(route) = PATTERN_FETCH.captures(path) {
+                (Some(Route::Fetch), self.handle_fetch(req, route))
             } else if let Some(route) = PATTERN_PULL.captures(path) {
-                self.handle_pull(req, route)
+                let (body_done, body_done_rx) = futures::sync::oneshot::channel();
+                opt_body_done = Some(body_done_rx);
+                (Some(Route::Pull), self.handle_pull(req, route, body_done))
+            } else if let Some(route) = PATTERN_CHUNKS.captures(path) {
+                (Some(Route::Chunks), self.handle_chunks(req, route))
+            } else if let Some(route) = PATTERN_READ.captures(path) {
+                let (body_done, body_done_rx) = futures::sync::oneshot::channel();
+                opt_body_done = Some(body_done_rx);
+                (Some(Route::Read), self.handle_read(req, route, body_done))
             } else {
-                future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2()
+                (
+                    Some(Route::Unknown),
+                    future::ok(Response::new().with_status(StatusCode::NotFound)).boxed2(),
+                )
             },
             &Method::Options => {
                 let mut response = Response::new().with_header(AccessControlAllowMethods(vec![
@@ -550,119 +1126,696 @@ where
                         .headers_mut()
                         .set(AccessControlAllowHeaders(headers.to_vec()));
                 };
-                future::ok(response).boxed2()
+                (Some(Route::Options), future::ok(response).boxed2())
             }
-            _ => future::ok(Response::new().with_status(StatusCode::MethodNotAllowed)).boxed2(),
-        }.then(|result| match result {
-            Ok(res) => Ok(res.with_header(AccessControlAllowOrigin::Any).into()),
-            Err(err) => Err(err.into()),
-        })
+            _ => (
+                Some(Route::Unknown),
+                future::ok(Response::new().with_status(StatusCode::MethodNotAllowed)).boxed2(),
+            ),
+        };
+        let metrics = self.metrics.clone();
+        let logger = self.logger.clone();
+        let remote = self.remote.clone();
+        fut.then(move |result| {
+            drop(request);
+            let route = match route {
+                Some(route) => route,
+                None => return result,
+            };
+            let (status, content_length) = match result {
+                Ok(ref res) => (
+                    Some(res.status().as_u16()),
+                    res.headers().get::<ContentLength>().map(|len| **len),
+                ),
+                Err(_) => (None, None),
+            };
+            let finish = move |bytes_out: Option<u64>| {
+                let elapsed = start.elapsed();
+                metrics.observe_request(route, elapsed);
+                logger.log(
+                    Level::Info,
+                    "request",
+                    json!({
+                        "method": method,
+                        "route": route.name(),
+                        "flow_id": flow_id,
+                        "status": status,
+                        "bytes_in": bytes_in,
+                        "bytes_out": bytes_out,
+                        "duration": elapsed.as_secs() as f64
+                            + elapsed.subsec_nanos() as f64 / 1e9,
+                    }),
+                );
+            };
+            match opt_body_done {
+                // A streamed request lasts until its body is sent, or not started at all.
+                Some(body_done) => remote.spawn(move |_| {
+                    body_done.then(move |sent| {
+                        finish(sent.ok().or(content_length));
+                        Ok(())
+                    })
+                }),
+                None => finish(content_length),
+            }
+            result
+        }).boxed2()
+            .then(|result| match result {
+                Ok(res) => Ok(res.with_header(AccessControlAllowOrigin::Any).into()),
+                Err(err) => Err(err.into()),
+            })
     }
 }
 
-fn start_service(
-    addr: std::net::SocketAddr,
+/// Serve the connection with HTTP/2 if it is negotiated, otherwise with HTTP/1.1.
+fn serve_connection<T, S>(
+    io: T,
+    service: S,
+    is_http2: bool,
+    max_concurrent_streams: u32,
+    handle: &reactor::Handle,
+) -> Box<Future<Item = (), Error = String>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+    S: Service<Request = Request, Response = Response, Error = HyperError> + 'static,
+{
+    if is_http2 {
+        Box::new(
+            http2::serve_connection(io, service, max_concurrent_streams, handle)
+                .map_err(|err| err.to_string()),
+        )
+    } else {
+        let http = Http::<hyper::Chunk>::new();
+        Box::new(
+            http.serve_connection(io, service)
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+        )
+    }
+}
+
+fn log_connection_dropped(
+    logger: &Logger,
+    worker: Option<usize>,
+    peer_addr: Option<std::net::SocketAddr>,
+    violation: Violation,
+) {
+    logger.log(
+        Level::Warn,
+        "connection_dropped",
+        json!({
+            "worker": worker,
+            "peer": peer_addr.map(|addr| addr.to_string()),
+            "reason": violation.name(),
+        }),
+    );
+}
+
+fn log_connection_error<E: fmt::Display>(
+    logger: &Logger,
+    event: &str,
+    worker: usize,
+    peer_addr: Option<std::net::SocketAddr>,
+    err: E,
+) {
+    logger.log(
+        Level::Warn,
+        event,
+        json!({
+            "worker": worker,
+            "peer": peer_addr.map(|addr| addr.to_string()),
+            "error": err.to_string(),
+        }),
+    );
+}
+
+struct ServiceHandle {
+    addrs: Vec<ListenAddr>,
+    pool: Arc<Pool>,
+    health: Arc<Health>,
+    logger: Arc<Logger>,
+    stopped: Arc<AtomicBool>,
+    accept_thds: Vec<thread::JoinHandle<()>>,
+    workers: Vec<(futures::sync::oneshot::Sender<()>, thread::JoinHandle<()>)>,
+}
+
+impl ServiceHandle {
+    /// Stop accepting, wait up to `deadline` for the active transfers, then abort the remaining
+    /// flows and stop the workers.
+    fn shutdown(self, deadline: Duration) {
+        self.health.set_draining();
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accept loops so they notice the stop flag.
+        for (addr, accept_thd) in self.addrs.iter().zip(self.accept_thds) {
+            listener::wake(addr);
+            accept_thd.join().unwrap();
+            if let ListenAddr::Unix(ref path) = *addr {
+                fs::remove_file(path).ok();
+            }
+        }
+
+        let start = Instant::now();
+        while self.health.get_transfers() > 0 && start.elapsed() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let transfers = self.health.get_transfers();
+        let flows = self.pool.list(None, usize::max_value());
+        for flow_ptr in flows.iter() {
+            flow_ptr.write().unwrap().expire();
+        }
+        self.logger.log(
+            Level::Info,
+            "shutdown",
+            json!({
+                "aborted_flows": flows.len(),
+                "aborted_transfers": transfers,
+            }),
+        );
+
+        for (exit_tx, worker_thd) in self.workers {
+            exit_tx.send(()).ok();
+            worker_thd.join().ok();
+        }
+    }
+}
+
+/// The settings of the service, taken from the config.
+struct ServiceOptions {
     num_worker: usize,
-    pool_size: Option<usize>,
     deactive_timeout: Option<Duration>,
+    max_ttl: Option<Duration>,
     meta_capacity: u64,
     data_capacity: u64,
-    tls_acceptor: Option<TlsAcceptor>,
-) -> (std::net::SocketAddr, thread::JoinHandle<()>) {
-    let upstream_listener = std::net::TcpListener::bind(&addr).unwrap();
-    let pool_ptr = Pool::new(pool_size, deactive_timeout);
-    let auth_ptr = Arc::new(HMACAuthorizer::new());
+    chunk_size: u64,
+    max_chunk_size: u64,
+    ready_threshold: f64,
+    max_concurrent_streams: u32,
+}
+
+impl<'a> From<&'a Config> for ServiceOptions {
+    fn from(config: &'a Config) -> Self {
+        ServiceOptions {
+            num_worker: config.num_worker,
+            deactive_timeout: Some(Duration::from_secs(config.deactive_timeout)),
+            max_ttl: Some(Duration::from_secs(config.max_ttl)),
+            meta_capacity: config.meta_capacity,
+            data_capacity: config.data_capacity,
+            chunk_size: config.chunk_size,
+            max_chunk_size: config.max_chunk_size,
+            ready_threshold: config.ready_threshold,
+            max_concurrent_streams: config.max_concurrent_streams,
+        }
+    }
+}
+
+impl Default for ServiceOptions {
+    fn default() -> Self {
+        ServiceOptions::from(&Config::default())
+    }
+}
+
+/// Serve on every listener, each given with whether it expects the PROXY protocol header.
+fn start_service(
+    listeners: Vec<(ListenAddr, bool)>,
+    options: ServiceOptions,
+    pool_ptr: Arc<Pool>,
+    auth_ptr: Arc<Authorizer>,
+    limits: Arc<Limits>,
+    metrics: Arc<Metrics>,
+    logger: Arc<Logger>,
+    tls_acceptor: Option<SharedAcceptor>,
+) -> ServiceHandle {
+    let ServiceOptions {
+        num_worker,
+        deactive_timeout,
+        max_ttl,
+        meta_capacity,
+        data_capacity,
+        chunk_size,
+        max_chunk_size,
+        ready_threshold,
+        max_concurrent_streams,
+    } = options;
+    let listeners: Vec<_> = listeners
+        .into_iter()
+        .map(|(addr, proxied)| (Listener::bind(&addr).unwrap(), proxied))
+        .collect();
+    let health = Health::new(num_worker, ready_threshold);
     let mut workers = Vec::with_capacity(num_worker);
+    let mut worker_handles = Vec::with_capacity(num_worker);
 
     for idx in 0..num_worker {
         // Size of backlog = 64.
-        let (io_tx, io_rx) = futures::sync::mpsc::channel::<std::net::TcpStream>(64);
+        let (io_tx, io_rx) = futures::sync::mpsc::channel::<
+            (ListenerStream, Option<std::net::SocketAddr>, bool),
+        >(64);
         let pool_ptr = pool_ptr.clone();
         let auth_ptr = auth_ptr.clone();
         let tls_acceptor = tls_acceptor.clone();
-        thread::spawn(move || {
+        let metrics = metrics.clone();
+        let logger = logger.clone();
+        let health = health.clone();
+        let limits = limits.clone();
+        let (exit_tx, exit_rx) = futures::sync::oneshot::channel();
+        let worker_thd = thread::spawn(move || {
             let mut core = Core::new().unwrap();
             let handle = core.handle();
             let remote = core.remote();
             // Create the corresponding binding function.
-            let bind_fn: Box<Fn(_, _) -> _> = if let Some(tls_acceptor) = tls_acceptor {
-                Box::new(move |io, service| {
-                    tls_acceptor
-                        .accept_async(io)
-                        .map_err(|_| ())
-                        .and_then(move |io| {
-                            let http = Http::<hyper::Chunk>::new();
-                            http.serve_connection(io, service)
-                                .map(|_| ())
-                                .map_err(|_| ())
+            let bind_logger = logger.clone();
+            let bind_handle = handle.clone();
+            let bind_fn: Box<
+                Fn(Monitored<AsyncStream>, Option<std::net::SocketAddr>, _)
+                    -> Box<Future<Item = (), Error = ()>>,
+            > = if let Some(tls_acceptor) = tls_acceptor {
+                    Box::new(move |io, peer_addr, service| {
+                        let tls_logger = bind_logger.clone();
+                        let http_logger = bind_logger.clone();
+                        let handle = bind_handle.clone();
+                        Box::new(
+                            tls_acceptor
+                                .accept_async(io)
+                                .map_err(move |err| {
+                                    log_connection_error(
+                                        &tls_logger,
+                                        "tls_handshake_failed",
+                                        idx,
+                                        peer_addr,
+                                        err,
+                                    )
+                                })
+                                .and_then(move |io| {
+                                    let client_subject = tls::peer_subject(&io);
+                                    let service = service.with_client_subject(client_subject);
+                                    // HTTP/2 is only spoken if the client asks for it by ALPN.
+                                    let is_http2 = tls::alpn_protocol(&io)
+                                        .map_or(false, |protocol| protocol == "h2");
+                                    serve_connection(
+                                        io,
+                                        service,
+                                        is_http2,
+                                        max_concurrent_streams,
+                                        &handle,
+                                    ).map_err(move |err| {
+                                        log_connection_error(
+                                            &http_logger,
+                                            "connection_error",
+                                            idx,
+                                            peer_addr,
+                                            err,
+                                        )
+                                    })
+                                }),
+                        )
+                    })
+                } else {
+                    Box::new(move |io, peer_addr, service| {
+                        let http_logger = bind_logger.clone();
+                        let handle = bind_handle.clone();
+                        Box::new(
+                            http2::detect_preface(io)
+                                .map_err(|err| err.to_string())
+                                .and_then(move |(io, is_http2)| {
+                                    serve_connection(
+                                        io,
+                                        service,
+                                        is_http2,
+                                        max_concurrent_streams,
+                                        &handle,
+                                    )
+                                })
+                                .map_err(move |err| {
+                                    log_connection_error(
+                                        &http_logger,
+                                        "connection_error",
+                                        idx,
+                                        peer_addr,
+                                        err,
+                                    )
+                                }),
+                        )
+                    })
+                };
+            let bind_fn = Rc::new(bind_fn);
+            if idx == 0 {
+                if let Some(deactive_timeout) = deactive_timeout {
+                    // Periodically sweep the idle flows on the first worker.
+                    let sweep_interval = cmp::max(deactive_timeout / 4, Duration::from_secs(1));
+                    let pool_ptr = pool_ptr.clone();
+                    let logger = logger.clone();
+                    handle.spawn(
+                        Interval::new(sweep_interval, &handle)
+                            .unwrap()
+                            .for_each(move |_| {
+                                let count = pool_ptr.sweep();
+                                if count > 0 {
+                                    logger.log(
+                                        Level::Info,
+                                        "flows_swept",
+                                        json!({ "count": count }),
+                                    );
+                                }
+                                Ok(())
+                            })
+                            .map_err(|_| ()),
+                    );
+                }
+            }
+            let _worker_guard = Health::start_worker(&health, idx);
+            logger.log(Level::Info, "worker_started", json!({ "worker": idx }));
+            // Keep serving the accepted connections until the service is shut down.
+            core.run(io_rx.for_each(|(io, peer_addr, proxied)| {
+                // The connection holds a slot from the accept, so clients stalling the PROXY
+                // header are limited too.
+                let mut connection = match Limits::acquire(&limits) {
+                    Ok(connection) => connection,
+                    Err(violation) => {
+                        metrics.add_dropped(violation);
+                        log_connection_dropped(&logger, Some(idx), peer_addr, violation);
+                        metrics.disconnect(idx);
+                        return Ok(());
+                    }
+                };
+                let io = io.into_async(&handle).unwrap();
+                // A pull may ask for chunks up to the max chunk size, 4x of it should be enough for
+                // sending any chunk.
+                io.set_send_buffer_size(max_chunk_size as usize * 4).unwrap();
+                let activity = Activity::new();
+                let io = Monitored::new(io, activity.clone());
+                // Behind a proxy, the client address comes from the PROXY header. Its slot is
+                // charged once it is known.
+                let header_fut = if proxied {
+                    Either::A(
+                        proxy::read_header(io)
+                            .map(move |(io, client_addr)| (io, client_addr.or(peer_addr))),
+                    )
+                } else {
+                    Either::B(future::ok::<_, IoError>((io, peer_addr)))
+                };
+                let client_addr = Rc::new(Cell::new(peer_addr));
+                let serve_fut = {
+                    let client_addr = client_addr.clone();
+                    let pool_ptr = pool_ptr.clone();
+                    let remote = remote.clone();
+                    let auth_ptr = auth_ptr.clone();
+                    let metrics = metrics.clone();
+                    let logger = logger.clone();
+                    let header_logger = logger.clone();
+                    let health = health.clone();
+                    let activity = activity.clone();
+                    let bind_fn = bind_fn.clone();
+                    header_fut
+                        .map_err(move |err| {
+                            log_connection_error(
+                                &header_logger,
+                                "proxy_header_failed",
+                                idx,
+                                peer_addr,
+                                err,
+                            )
                         })
-                        .boxed2()
-                })
-            } else {
-                Box::new(move |io, service| {
-                    let http = Http::<hyper::Chunk>::new();
-                    http.serve_connection(io, service)
-                        .map(|_| ())
-                        .map_err(|_| ())
-                        .boxed2()
-                })
-            };
-            println!("Worker #{} is started.", idx);
-            core.run(io_rx.for_each(|io| {
-                let io = tokio::net::TcpStream::from_stream(io, &handle).unwrap();
-                // 4x REF_SIZE should be enough for sending a chunk.
-                io.set_send_buffer_size(flow::REF_SIZE * 4).unwrap();
-                let service = FlowService::new(
-                    pool_ptr.clone(),
-                    remote.clone(),
-                    meta_capacity,
-                    data_capacity,
-                    auth_ptr.clone(),
-                );
-                handle.spawn(bind_fn(io, service));
+                        .and_then(move |(io, peer_addr)| {
+                            client_addr.set(peer_addr);
+                            if let Err(violation) =
+                                connection.charge(peer_addr.map(|addr| addr.ip()))
+                            {
+                                metrics.add_dropped(violation);
+                                log_connection_dropped(&logger, Some(idx), peer_addr, violation);
+                                return Either::B(future::err(()));
+                            }
+                            let service = FlowService::new(
+                                pool_ptr,
+                                remote,
+                                meta_capacity,
+                                data_capacity,
+                                chunk_size,
+                                max_chunk_size,
+                                max_ttl.map(|max_ttl| max_ttl.as_secs()),
+                                deactive_timeout.map(|deactive_timeout| deactive_timeout.as_secs()),
+                                auth_ptr,
+                                metrics,
+                                logger,
+                                health,
+                                activity,
+                                connection.get_bucket(),
+                            );
+                            Either::A(bind_fn(io, peer_addr, service).then(move |ret| {
+                                drop(connection);
+                                ret
+                            }))
+                        })
+                };
+                // Close the connection once it breaks the limits.
+                let watch_limits = limits.clone();
+                let watchdog = Interval::new(Duration::from_secs(1), &handle)
+                    .unwrap()
+                    .filter_map(move |_| watch_limits.check(&activity))
+                    .into_future()
+                    .map_err(|_| ());
+                // The connection is counted by the accept thread when it is dispatched.
+                let metrics = metrics.clone();
+                let logger = logger.clone();
+                handle.spawn(serve_fut.select2(watchdog).then(move |ret| {
+                    if let Ok(Either::B(((Some(violation), _), _))) = ret {
+                        metrics.add_dropped(violation);
+                        log_connection_dropped(&logger, Some(idx), client_addr.get(), violation);
+                    }
+                    metrics.disconnect(idx);
+                    Ok(())
+                }));
                 Ok(())
-            })).unwrap();
+            }).then(|_| exit_rx)).ok();
         });
         workers.push(io_tx);
+        worker_handles.push((exit_tx, worker_thd));
     }
-    let bind_addr = upstream_listener.local_addr().unwrap();
-    let service_thd = thread::spawn(move || {
-        for (idx, io) in upstream_listener.incoming().enumerate() {
-            workers[idx % workers.len()]
-                .clone()
-                .send(io.unwrap())
-                .wait()
-                .unwrap();
+    let addrs: Vec<_> = listeners
+        .iter()
+        .map(|&(ref listener, _)| listener.local_addr().unwrap())
+        .collect();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let accept_thds = listeners
+        .into_iter()
+        .map(|(listener, proxied)| {
+            let mut workers = workers.clone();
+            let stopped = stopped.clone();
+            let metrics = metrics.clone();
+            let logger = logger.clone();
+            thread::spawn(move || {
+                let mut start = 0;
+                loop {
+                    let accepted = listener.accept();
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let (io, peer_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            logger.log(
+                                Level::Warn,
+                                "accept_failed",
+                                json!({ "error": err.to_string() }),
+                            );
+                            // Errors like running out of file descriptors last for a while, so
+                            // back off instead of spinning on them.
+                            thread::sleep(Duration::from_millis(100));
+                            continue;
+                        }
+                    };
+                    let connections: Vec<_> = (0..workers.len())
+                        .map(|idx| metrics.get_connections(idx))
+                        .collect();
+                    start = (start + 1) % workers.len();
+                    // Never wait for a busy worker, try the next least loaded one instead.
+                    let mut io = Some((io, peer_addr, proxied));
+                    for idx in worker_order(&connections, start) {
+                        metrics.connect(idx);
+                        match workers[idx].try_send(io.take().unwrap()) {
+                            Ok(_) => break,
+                            Err(err) => {
+                                metrics.disconnect(idx);
+                                io = Some(err.into_inner());
+                            }
+                        }
+                    }
+                    if io.is_some() {
+                        logger.log(
+                            Level::Warn,
+                            "connection_rejected",
+                            json!({ "peer": peer_addr.map(|addr| addr.to_string()) }),
+                        );
+                    }
+                }
+            })
+        })
+        .collect();
+    ServiceHandle {
+        addrs,
+        pool: pool_ptr,
+        health,
+        logger,
+        stopped,
+        accept_thds,
+        workers: worker_handles,
+    }
+}
+
+/// Order the workers by their connections, least first. Ties are broken round-robin from
+/// `start`, so idle workers take turns.
+fn worker_order(connections: &[usize], start: usize) -> Vec<usize> {
+    let num_worker = connections.len();
+    let mut order: Vec<_> = (0..num_worker).collect();
+    order.sort_by_key(|&idx| (connections[idx], (idx + num_worker - start) % num_worker));
+    order
+}
+
+/// A failed reload keeps serving the old certificate, but makes the service unready.
+fn reload_tls(logger: &Logger, health: &Health, result: Result<bool, String>) {
+    match result {
+        Ok(true) => {
+            health.set_tls(true);
+            logger.log(Level::Info, "tls_reloaded", json!({}));
+        }
+        Ok(false) => (),
+        Err(err) => {
+            health.set_tls(false);
+            logger.log(Level::Warn, "tls_reload_failed", json!({ "error": err }));
         }
-    });
-    (bind_addr, service_thd)
+    }
 }
 
 fn main() {
     dotenv().ok();
-    let addr: std::net::SocketAddr = env::var("SERVER_ADDRESS").unwrap().parse().unwrap();
-    let num_worker: usize = env::var("NUM_WORKER").unwrap().parse().unwrap();
-    let pool_size: usize = env::var("POOL_SIZE").unwrap().parse().unwrap();
-    let deactive_timeout: u64 = env::var("DEACTIVE_TIMEOUT").unwrap().parse().unwrap();
-    let meta_capacity: u64 = env::var("META_CAPACITY").unwrap().parse().unwrap();
-    let data_capacity: u64 = env::var("DATA_CAPACITY").unwrap().parse().unwrap();
-    #[cfg(target_os = "windows")]
-    let tls_acceptor = tls::build_tls_from_pfx(&env::var("TLS_PFX").unwrap());
-    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
-    let tls_acceptor = tls::build_tls_from_pem(
-        &env::var("TLS_CERT").unwrap(),
-        &env::var("TLS_PRIVATE").unwrap(),
-    );
-    let (_, service_thd) = start_service(
-        addr,
-        num_worker,
-        Some(pool_size),
-        Some(Duration::from_secs(deactive_timeout)),
-        meta_capacity,
-        data_capacity,
-        Some(tls_acceptor),
+    let config = match config::parse_args(env::args().skip(1))
+        .and_then(|options| Config::load(&options).map(|config| (config, options.print_config)))
+    {
+        Ok((config, true)) => {
+            print!("{}", config.to_toml());
+            return;
+        }
+        Ok((config, false)) => config,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, config::USAGE);
+            process::exit(2);
+        }
+    };
+    let tls_source = match (&config.tls_cert, &config.tls_private, &config.tls_pfx) {
+        (&Some(ref cert_path), &Some(ref priv_path), _) => Some(TlsSource::Pem {
+            cert_path: cert_path.to_owned(),
+            priv_path: priv_path.to_owned(),
+            sni: config.tls_sni.clone(),
+            client_ca: config.tls_client_ca.clone(),
+        }),
+        (_, _, &Some(ref pfx_path)) => Some(TlsSource::Pfx {
+            pfx_path: pfx_path.to_owned(),
+        }),
+        _ => None,
+    };
+    let tls_acceptor = match tls_source.map(SharedAcceptor::new) {
+        Some(Ok(acceptor)) => Some(acceptor),
+        Some(Err(err)) => {
+            eprintln!("Failed to load the TLS certificate: {}", err);
+            process::exit(1);
+        }
+        None => None,
+    };
+    let logger = match config.log_file {
+        Some(ref path) => match Logger::open(config.log_level, path) {
+            Ok(logger) => logger,
+            Err(err) => {
+                eprintln!("Failed to open '{}': {}", path, err);
+                process::exit(1);
+            }
+        },
+        None => Logger::stdout(config.log_level),
+    };
+    let auth_ptr: Arc<Authorizer> = if config.tls_client_ca.is_some() {
+        Arc::new(CertAuthorizer::new(config.tls_client_subjects.clone()))
+    } else {
+        Arc::new(HMACAuthorizer::new())
+    };
+    let metrics = Metrics::new(config.num_worker);
+    let seconds = |secs| if secs > 0 { Some(Duration::from_secs(secs)) } else { None };
+    let limits = Limits::new(
+        config.max_connections,
+        config.max_connections_per_ip,
+        seconds(config.header_timeout),
+        seconds(config.connection_idle_timeout),
+        config.min_throughput,
+        config.max_rate_per_ip,
+    );
+    let deactive_timeout = Duration::from_secs(config.deactive_timeout);
+    let pool_ptr = Pool::new(
+        config.num_shard,
+        Some(config.pool_size),
+        Some(deactive_timeout),
     );
-    service_thd.join().unwrap();
+    let admin = (config.admin_address, config.admin_token.clone());
+    if let (Some(admin_addr), Some(admin_token)) = admin {
+        admin::start_service(
+            admin_addr,
+            admin_token,
+            pool_ptr.clone(),
+            metrics.clone(),
+            logger.clone(),
+        );
+    }
+    let listeners = config
+        .server_address
+        .0
+        .iter()
+        .map(|addr| (addr.clone(), false))
+        .chain(config.proxy_address.0.iter().map(|addr| (addr.clone(), true)))
+        .collect();
+    let service = start_service(
+        listeners,
+        ServiceOptions::from(&config),
+        pool_ptr,
+        auth_ptr,
+        limits,
+        metrics,
+        logger.clone(),
+        tls_acceptor.clone(),
+    );
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    if let Some(tls_acceptor) = tls_acceptor {
+        #[cfg(unix)]
+        {
+            let tls_acceptor = tls_acceptor.clone();
+            let logger = logger.clone();
+            let health = service.health.clone();
+            handle.spawn(
+                tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP, &handle)
+                    .flatten_stream()
+                    .for_each(move |_| {
+                        reload_tls(&logger, &health, tls_acceptor.reload().map(|_| true));
+                        Ok(())
+                    })
+                    .map_err(|_| ()),
+            );
+        }
+        if config.tls_reload_interval > 0 {
+            let health = service.health.clone();
+            handle.spawn(
+                Interval::new(Duration::from_secs(config.tls_reload_interval), &handle)
+                    .unwrap()
+                    .for_each(move |_| {
+                        reload_tls(&logger, &health, tls_acceptor.reload_if_modified());
+                        Ok(())
+                    })
+                    .map_err(|_| ()),
+            );
+        }
+    }
+    #[cfg(unix)]
+    let signal =
+        tokio_signal::unix::Signal::new(tokio_signal::unix::SIGTERM, &handle).flatten_stream();
+    #[cfg(not(unix))]
+    let signal = tokio_signal::ctrl_c(&handle).flatten_stream();
+    core.run(signal.into_future()).ok();
+    service.shutdown(Duration::from_secs(config.shutdown_timeout));
+    logger.flush();
 }
 
 #[cfg(test)]
@@ -675,19 +1828,44 @@ mod tests {
     use tokio_tls::{TlsConnectorExt, TlsStream};
 
     const MAX_CAPACITY: u64 = 1048576;
+    const MAX_CHUNK_SIZE: u64 = 262144;
     const DEFL_FLOW_PARAM: &str = r#"{"preserve_mode": false}"#;
 
+    fn tcp_addr(service: &ServiceHandle) -> std::net::SocketAddr {
+        match service.addrs[0] {
+            ListenAddr::Tcp(addr) => addr,
+            _ => unreachable!(),
+        }
+    }
+
+    fn options() -> ServiceOptions {
+        ServiceOptions {
+            num_worker: 1,
+            deactive_timeout: None,
+            max_ttl: None,
+            meta_capacity: MAX_CAPACITY,
+            data_capacity: MAX_CAPACITY,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            ready_threshold: 1.0,
+            ..Default::default()
+        }
+    }
+
     fn spawn_server() -> String {
-        let (bind_addr, _) = start_service(
-            "127.0.0.1:0".parse().unwrap(),
-            1,
-            Some(32),
-            Some(Duration::from_secs(6)),
-            MAX_CAPACITY,
-            MAX_CAPACITY,
+        let service = start_service(
+            vec![("127.0.0.1:0".parse().unwrap(), false)],
+            ServiceOptions {
+                deactive_timeout: Some(Duration::from_secs(6)),
+                ..options()
+            },
+            Pool::new(4, Some(32), Some(Duration::from_secs(6))),
+            Arc::new(HMACAuthorizer::new()),
+            Limits::new(0, 0, None, None, 0, 0),
+            Metrics::new(1),
+            Logger::new(Level::Error, Box::new(io::sink())),
             None,
         );
-        format!("http://127.0.0.1:{}", bind_addr.port())
+        format!("http://127.0.0.1:{}", tcp_addr(&service).port())
     }
 
     fn create_flow(prefix: &str, param: &str) -> (String, String) {
@@ -805,6 +1983,60 @@ mod tests {
         (status_code, response)
     }
 
+    fn req_chunks(
+        prefix: &str,
+        flow_id: &str,
+        query: &str,
+    ) -> (StatusCode, Option<ChunksResponse>) {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+
+        let req = Request::new(
+            Method::Get,
+            format!("{}/flow/{}/chunks?{}", prefix, flow_id, query)
+                .parse()
+                .unwrap(),
+        );
+
+        core.run(client.request(req).and_then(|res| {
+            let status_code = res.status();
+            res.body().concat2().and_then(move |body| {
+                let response = if status_code == StatusCode::Ok {
+                    Some(serde_json::from_slice::<ChunksResponse>(&body).unwrap())
+                } else {
+                    None
+                };
+                Ok((status_code, response))
+            })
+        })).unwrap()
+    }
+
+    fn req_read(prefix: &str, flow_id: &str, query: &str) -> (StatusCode, Option<Vec<u8>>) {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+
+        let req = Request::new(
+            Method::Get,
+            format!("{}/flow/{}/read?{}", prefix, flow_id, query)
+                .parse()
+                .unwrap(),
+        );
+
+        core.run(client.request(req).and_then(|res| {
+            let status_code = res.status();
+            let content_length = res.headers().get::<ContentLength>().map(|len| **len);
+            res.body().concat2().and_then(move |body| {
+                if status_code != StatusCode::Ok {
+                    return Ok((status_code, None));
+                }
+                if let Some(content_length) = content_length {
+                    assert_eq!(content_length, body.len() as u64);
+                }
+                Ok((status_code, Some(body.to_vec())))
+            })
+        })).unwrap()
+    }
+
     fn req_fetch(prefix: &str, flow_id: &str, index: u64) -> (StatusCode, Option<Vec<u8>>) {
         let mut core = Core::new().unwrap();
         let client = Client::new(&core.handle());
@@ -836,9 +2068,28 @@ mod tests {
                 assert!(check_immutable && check_maxage);
             }
             let fut = if status_code == StatusCode::Ok {
+                let digest = res.headers().get_raw("Digest").unwrap().one().unwrap().to_vec();
+                let crc32c = res.headers()
+                    .get_raw("X-Checksum-CRC32C")
+                    .unwrap()
+                    .one()
+                    .unwrap()
+                    .to_vec();
                 res.body()
                     .concat2()
-                    .and_then(|body| Ok(Some(body.to_vec())))
+                    .and_then(move |body| {
+                        // Each chunk carries its own checksums.
+                        let sha256 = ring::digest::digest(&ring::digest::SHA256, &body);
+                        assert_eq!(
+                            digest,
+                            format!("sha-256={}", base64::encode(sha256.as_ref())).into_bytes()
+                        );
+                        assert_eq!(
+                            crc32c,
+                            format!("{:08x}", crc::crc32::checksum_castagnoli(&body)).into_bytes()
+                        );
+                        Ok(Some(body.to_vec()))
+                    })
                     .boxed2()
             } else {
                 future::ok(None).boxed2()
@@ -1294,7 +2545,7 @@ mod tests {
         let prefix = &spawn_server();
         let mut core = Core::new().unwrap();
         let handle = &core.handle();
-        let payload = vec![1u8; flow::REF_SIZE * 10];
+        let payload = vec![1u8; flow::DEFAULT_CHUNK_SIZE * 10];
         let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
         let fake_id = "bdc62e9323003d0f5cb44c8c745a0470";
 
@@ -1307,7 +2558,7 @@ mod tests {
                 let prefix = &prefix;
                 let flow_id = &flow_id;
                 let token = &token;
-                for chunk in payload.chunks(flow::REF_SIZE * 2 + 13) {
+                for chunk in payload.chunks(flow::DEFAULT_CHUNK_SIZE * 2 + 13) {
                     assert_eq!(
                         req_push(prefix, flow_id, token, chunk),
                         (StatusCode::Ok, None)
@@ -1446,6 +2697,141 @@ mod tests {
         thd.join().unwrap();
     }
 
+    #[test]
+    fn sweep_idle() {
+        let prefix = &spawn_server();
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::Ok, None)
+        );
+        let thd = {
+            let prefix = prefix.to_owned();
+            let flow_id = flow_id.to_owned();
+            thread::spawn(move || {
+                assert_eq!(
+                    req_fetch(&prefix, &flow_id, 1),
+                    (StatusCode::InternalServerError, None)
+                );
+            })
+        };
+
+        thread::sleep(Duration::from_secs(8));
+        assert_eq!(req_status(prefix, flow_id), (StatusCode::NotFound, None));
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::NotFound, None)
+        );
+        thd.join().unwrap();
+    }
+
+    fn req_probe<T: DeserializeOwned>(prefix: &str, path: &str) -> (StatusCode, T) {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let req = Request::new(Method::Get, format!("{}{}", prefix, path).parse().unwrap());
+        core.run(client.request(req).and_then(|res| {
+            let status_code = res.status();
+            res.body().concat2().and_then(move |body| {
+                Ok((status_code, serde_json::from_slice::<T>(&body).unwrap()))
+            })
+        })).unwrap()
+    }
+
+    #[test]
+    fn health_probes() {
+        let prefix = &spawn_server();
+        assert_eq!(
+            req_probe(prefix, "/healthz"),
+            (
+                StatusCode::Ok,
+                health::HealthResponse {
+                    alive: true,
+                    workers: vec![true],
+                }
+            )
+        );
+        assert_eq!(
+            req_probe(prefix, "/readyz"),
+            (
+                StatusCode::Ok,
+                health::ReadyResponse {
+                    ready: true,
+                    saturated: false,
+                    tls: true,
+                    draining: false,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let service = start_service(
+            vec![("127.0.0.1:0".parse().unwrap(), false)],
+            options(),
+            Pool::new(4, Some(32), None),
+            Arc::new(HMACAuthorizer::new()),
+            Limits::new(0, 0, None, None, 0, 0),
+            Metrics::new(1),
+            Logger::new(Level::Error, Box::new(io::sink())),
+            None,
+        );
+        let addr = tcp_addr(&service);
+        let prefix = &format!("http://127.0.0.1:{}", addr.port());
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::Ok, None)
+        );
+
+        // Keep a connection alive to talk to the draining service.
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let req = Request::new(
+            Method::Post,
+            format!("{}/flow/{}/status", prefix, flow_id)
+                .parse()
+                .unwrap(),
+        );
+        let status_code = core.run(client.request(req).and_then(|res| {
+            let status_code = res.status();
+            res.body().concat2().map(move |_| status_code)
+        })).unwrap();
+        assert_eq!(status_code, StatusCode::Ok);
+
+        let pull_thd = {
+            let url = format!("{}/flow/{}/pull", prefix, flow_id);
+            thread::spawn(move || {
+                let mut core = Core::new().unwrap();
+                let client = Client::new(&core.handle());
+                let req = Request::new(Method::Get, url.parse().unwrap());
+                // The unfinished flow is aborted, so the body must not end cleanly.
+                assert!(
+                    core.run(client
+                        .request(req)
+                        .and_then(|res| res.body().concat2()))
+                        .is_err()
+                );
+            })
+        };
+        thread::sleep(Duration::from_millis(500));
+
+        let shutdown_thd = thread::spawn(move || service.shutdown(Duration::from_secs(2)));
+        thread::sleep(Duration::from_millis(500));
+        let mut req = Request::new(Method::Post, format!("{}/new", prefix).parse().unwrap());
+        req.set_body(DEFL_FLOW_PARAM);
+        req.headers_mut()
+            .set(ContentLength(DEFL_FLOW_PARAM.len() as u64));
+        let status_code = core.run(client.request(req).map(|res| res.status()))
+            .unwrap();
+        assert_eq!(status_code, StatusCode::ServiceUnavailable);
+
+        shutdown_thd.join().unwrap();
+        pull_thd.join().unwrap();
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+
     #[test]
     fn dropped() {
         let prefix = &spawn_server();
@@ -1538,7 +2924,7 @@ mod tests {
                 let body_stream = stream::unfold((), move |_| {
                     send_tx.send(()).unwrap();
                     Some(future::ok((
-                        Ok(hyper::Chunk::from(vec![0u8; flow::REF_SIZE])),
+                        Ok(hyper::Chunk::from(vec![0u8; flow::DEFAULT_CHUNK_SIZE])),
                         (),
                     )))
                 });
@@ -1673,9 +3059,475 @@ mod tests {
                     next: 2,
                     dropped: 0,
                     pushed: 10,
+                    lifetime: Some(6),
+                    ..Default::default()
+                }),
+            )
+        );
+    }
+
+    #[test]
+    fn flow_metadata() {
+        let prefix = &spawn_server();
+        let mut core = Core::new().unwrap();
+        let handle = &core.handle();
+
+        let mut metadata = BTreeMap::new();
+        metadata.insert("origin".to_owned(), "camera-1".to_owned());
+        let param = serde_json::to_vec(&NewRequest {
+            content_type: Some("text/plain; charset=utf-8".into()),
+            filename: Some("hello.txt".into()),
+            metadata: Some(metadata.clone()),
+            ..Default::default()
+        }).unwrap();
+        let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::Ok, None)
+        );
+        assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+        let (_, status) = req_status(prefix, flow_id);
+        let status = status.unwrap();
+        assert_eq!(status.content_type, Some("text/plain; charset=utf-8".into()));
+        assert_eq!(status.filename, Some("hello.txt".into()));
+        assert_eq!(status.metadata, Some(metadata));
+
+        let req = Request::new(
+            Method::Get,
+            format!("{}/flow/{}/pull", prefix, flow_id).parse().unwrap(),
+        );
+        core.run({
+            let client = Client::new(handle);
+            client.request(req).and_then(|res| {
+                assert_eq!(
+                    res.headers().get::<ContentType>(),
+                    Some(&ContentType("text/plain; charset=utf-8".parse().unwrap()))
+                );
+                let content_disp = res.headers().get::<ContentDisposition>().unwrap();
+                assert_eq!(content_disp.disposition, DispositionType::Attachment);
+                match content_disp.parameters[0] {
+                    DispositionParam::Filename(_, _, ref filename) => {
+                        assert_eq!(filename, b"hello.txt")
+                    }
+                    _ => panic!("no filename"),
+                }
+                Ok(())
+            })
+        }).unwrap();
+
+        // Without one at creation, the content type comes from the first push.
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        for content_type in ["image/png", "text/html"].iter() {
+            let mut req = Request::new(
+                Method::Post,
+                format!("{}/flow/{}/push?token={}", prefix, flow_id, token)
+                    .parse()
+                    .unwrap(),
+            );
+            req.headers_mut()
+                .set(ContentType(content_type.parse().unwrap()));
+            req.set_body("Hello");
+            core.run({
+                let client = Client::new(handle);
+                client.request(req).and_then(|res| {
+                    assert_eq!(res.status(), StatusCode::Ok);
+                    Ok(())
+                })
+            }).unwrap();
+        }
+        let (_, status) = req_status(prefix, flow_id);
+        assert_eq!(status.unwrap().content_type, Some("image/png".into()));
+
+        // The type of a form submission is not the type of the data.
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        let mut req = Request::new(
+            Method::Post,
+            format!("{}/flow/{}/push?token={}", prefix, flow_id, token)
+                .parse()
+                .unwrap(),
+        );
+        req.headers_mut().set(ContentType::form_url_encoded());
+        req.set_body("Hello");
+        core.run({
+            let client = Client::new(handle);
+            client.request(req).and_then(|res| {
+                assert_eq!(res.status(), StatusCode::Ok);
+                Ok(())
+            })
+        }).unwrap();
+        let (_, status) = req_status(prefix, flow_id);
+        assert_eq!(status.unwrap().content_type, None);
+
+        // Only the types without scripts are shown inline, and none of them are sniffed.
+        for &(content_type, is_inline) in [("image/png", true), ("text/html", false)].iter() {
+            let param = serde_json::to_vec(&NewRequest {
+                content_type: Some(content_type.into()),
+                ..Default::default()
+            }).unwrap();
+            let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
+            assert_eq!(
+                req_push(prefix, flow_id, token, b"Hello"),
+                (StatusCode::Ok, None)
+            );
+            assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+            let req = Request::new(
+                Method::Get,
+                format!("{}/flow/{}/pull", prefix, flow_id).parse().unwrap(),
+            );
+            core.run({
+                let client = Client::new(handle);
+                client.request(req).and_then(move |res| {
+                    assert_eq!(
+                        res.headers().get_raw("X-Content-Type-Options").unwrap().one(),
+                        Some(&b"nosniff"[..])
+                    );
+                    match res.headers().get::<ContentDisposition>() {
+                        Some(content_disp) => {
+                            assert!(!is_inline);
+                            assert_eq!(content_disp.disposition, DispositionType::Attachment);
+                            assert!(content_disp.parameters.is_empty());
+                        }
+                        None => assert!(is_inline),
+                    }
+                    Ok(())
+                })
+            }).unwrap();
+        }
+
+        for param in [
+            r#"{"preserve_mode": false, "content_type": "text"}"#.to_owned(),
+            format!(
+                r#"{{"preserve_mode": false, "metadata": {{"key": "{}"}}}}"#,
+                "A".repeat(MAX_METADATA_SIZE)
+            ),
+        ].iter()
+        {
+            let mut req = Request::new(Method::Post, format!("{}/new", prefix).parse().unwrap());
+            req.set_body(param.to_owned());
+            req.headers_mut().set(ContentLength(param.len() as u64));
+            core.run({
+                let client = Client::new(handle);
+                client
+                    .request(req)
+                    .and_then(|res| check_error_response(res, "Invalid Parameter"))
+            }).unwrap();
+        }
+    }
+
+    #[test]
+    fn digest() {
+        const HELLO_WORLD: &str = "872e4e50ce9990d8b041330c47c9ddd11bec6b503ae9386a99da8584e9bb12c4";
+        let prefix = &spawn_server();
+        let mut core = Core::new().unwrap();
+        let handle = &core.handle();
+
+        // The expected digest is accepted in uppercase too.
+        let param = format!(
+            r#"{{"size": 10, "preserve_mode": false, "sha256": "{}"}}"#,
+            HELLO_WORLD.to_uppercase()
+        );
+        let (ref flow_id, ref token) = create_flow(prefix, &param);
+        let req = Request::new(
+            Method::Get,
+            format!("{}/flow/{}/pull", prefix, flow_id).parse().unwrap(),
+        );
+        let pull_fut = Client::new(handle).request(req).and_then(|res| {
+            assert_eq!(
+                res.headers().get_raw("Digest").unwrap().one(),
+                Some(&b"sha-256=hy5OUM6ZkNiwQTMMR8nd0Rvsa1A66ThqmdqFhOm7EsQ="[..])
+            );
+            res.body().concat2()
+        });
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"HelloWorld"),
+            (StatusCode::Ok, None)
+        );
+        assert_eq!(core.run(pull_fut).unwrap().as_ref(), b"HelloWorld");
+        let (_, status) = req_status(prefix, flow_id);
+        assert_eq!(status.unwrap().sha256, Some(HELLO_WORLD.into()));
+
+        // A fixed-length flow is checked by the push reaching its length.
+        let (ref flow_id, ref token) = create_flow(prefix, &param);
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"HelloWorlD"),
+            (StatusCode::BadRequest, Some("Digest Mismatch".into()))
+        );
+
+        // The expected digest is not sent before the data is known to match it.
+        let (ref flow_id, ref token) = create_flow(prefix, &param);
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::Ok, None)
+        );
+        let req = Request::new(
+            Method::Get,
+            format!("{}/flow/{}/pull", prefix, flow_id).parse().unwrap(),
+        );
+        core.run(Client::new(handle).request(req).map(|res| {
+            assert!(res.headers().get_raw("Digest").is_none());
+        })).unwrap();
+
+        // A digest given with the EOF must match too.
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::Ok, None)
+        );
+        let (_, status) = req_status(prefix, flow_id);
+        assert_eq!(status.unwrap().sha256, None);
+        assert_eq!(
+            req_close(prefix, flow_id, &format!("{}&sha256=xyz", token)),
+            (StatusCode::BadRequest, Some("Invalid Parameter".into()))
+        );
+        assert_eq!(
+            req_close(prefix, flow_id, &format!("{}&sha256={}", token, HELLO_WORLD)),
+            (StatusCode::BadRequest, Some("Digest Mismatch".into()))
+        );
+        let (_, status) = req_status(prefix, flow_id);
+        assert_eq!(status.unwrap().sha256, None);
+        // The mismatched flow is left open.
+        assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"HelloWorld"),
+            (StatusCode::Ok, None)
+        );
+        assert_eq!(
+            req_close(prefix, flow_id, &format!("{}&sha256={}", token, HELLO_WORLD)),
+            (StatusCode::Ok, None)
+        );
+
+        let param = r#"{"preserve_mode": false, "sha256": "872e4e50"}"#;
+        let mut req = Request::new(Method::Post, format!("{}/new", prefix).parse().unwrap());
+        req.set_body(param);
+        req.headers_mut().set(ContentLength(param.len() as u64));
+        core.run({
+            let client = Client::new(handle);
+            client
+                .request(req)
+                .and_then(|res| check_error_response(res, "Invalid Parameter"))
+        }).unwrap();
+    }
+
+    #[test]
+    fn list_chunks() {
+        let prefix = &spawn_server();
+        let param = format!(
+            r#"{{"preserve_mode": false, "chunk_size": {}}}"#,
+            flow::MIN_CHUNK_SIZE
+        );
+        let (ref flow_id, ref token) = create_flow(prefix, &param);
+        let payload: Vec<u8> = (0..2500).map(|num: u32| num as u8).collect();
+        assert_eq!(
+            req_push(prefix, flow_id, token, &payload),
+            (StatusCode::Ok, None)
+        );
+        assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+        assert_eq!(
+            req_fetch(prefix, flow_id, 0),
+            (StatusCode::Ok, Some(payload[..1024].to_vec()))
+        );
+
+        let (status_code, response) = req_chunks(prefix, flow_id, "");
+        assert_eq!(status_code, StatusCode::Ok);
+        let chunks = response.unwrap().chunks;
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| (chunk.index, chunk.start, chunk.end, chunk.length, chunk.pulled))
+                .collect::<Vec<_>>(),
+            vec![(0, 0, 1024, 1024, 1), (1, 1024, 2048, 1024, 0), (2, 2048, 2500, 452, 0)]
+        );
+        for chunk in chunks.iter() {
+            let data = &payload[chunk.start as usize..chunk.end as usize];
+            let sha256 = ring::digest::digest(&ring::digest::SHA256, data);
+            assert_eq!(chunk.checksum.sha256, utils::hex(sha256.as_ref()));
+        }
+
+        let (_, response) = req_chunks(prefix, flow_id, "from=1&limit=1");
+        assert_eq!(response.unwrap().chunks, vec![chunks[1].clone()]);
+        let (_, response) = req_chunks(prefix, flow_id, "from=3");
+        assert_eq!(response.unwrap().chunks, vec![]);
+        for query in ["limit=0", "limit=1001", "from=-1"].iter() {
+            assert_eq!(
+                req_chunks(prefix, flow_id, query),
+                (StatusCode::BadRequest, None)
+            );
+        }
+        let fake_id = "bdc62e9323003d0f5cb44c8c745a0470";
+        assert_eq!(
+            req_chunks(prefix, fake_id, ""),
+            (StatusCode::NotFound, None)
+        );
+    }
+
+    #[test]
+    fn read_bytes() {
+        let prefix = &spawn_server();
+        let param = format!(
+            r#"{{"preserve_mode": false, "chunk_size": {}}}"#,
+            flow::MIN_CHUNK_SIZE
+        );
+        let (ref flow_id, ref token) = create_flow(prefix, &param);
+        let payload: Vec<u8> = (0..2500).map(|num: u32| num as u8).collect();
+        assert_eq!(
+            req_push(prefix, flow_id, token, &payload),
+            (StatusCode::Ok, None)
+        );
+        assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+        assert_eq!(
+            req_read(prefix, flow_id, "offset=1000&length=100"),
+            (StatusCode::Ok, Some(payload[1000..1100].to_vec()))
+        );
+        assert_eq!(
+            req_read(prefix, flow_id, "offset=2000"),
+            (StatusCode::Ok, Some(payload[2000..].to_vec()))
+        );
+        assert_eq!(
+            req_read(prefix, flow_id, "offset=2400&length=500"),
+            (StatusCode::Ok, Some(payload[2400..].to_vec()))
+        );
+        assert_eq!(
+            req_read(prefix, flow_id, "offset=2500"),
+            (StatusCode::RangeNotSatisfiable, None)
+        );
+        for query in ["", "offset=-1", "offset=0&length=0"].iter() {
+            assert_eq!(
+                req_read(prefix, flow_id, query),
+                (StatusCode::BadRequest, None)
+            );
+        }
+        let fake_id = "bdc62e9323003d0f5cb44c8c745a0470";
+        assert_eq!(
+            req_read(prefix, fake_id, "offset=0"),
+            (StatusCode::NotFound, None)
+        );
+
+        // Reads wait for the data not pushed yet.
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        let push_thd = {
+            let prefix = prefix.to_owned();
+            let flow_id = flow_id.to_owned();
+            let token = token.to_owned();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                assert_eq!(
+                    req_push(&prefix, &flow_id, &token, b"Hello"),
+                    (StatusCode::Ok, None)
+                );
+                assert_eq!(
+                    req_push(&prefix, &flow_id, &token, b"World"),
+                    (StatusCode::Ok, None)
+                );
+            })
+        };
+        assert_eq!(
+            req_read(prefix, flow_id, "offset=3&length=4"),
+            (StatusCode::Ok, Some(b"loWo".to_vec()))
+        );
+        push_thd.join().unwrap();
+    }
+
+    #[test]
+    fn chunk_size() {
+        let prefix = &spawn_server();
+        let mut core = Core::new().unwrap();
+        let handle = &core.handle();
+
+        for chunk_size in [0, flow::MIN_CHUNK_SIZE as u64 - 1, MAX_CHUNK_SIZE + 1].iter() {
+            let param = serde_json::to_string(&NewRequest {
+                chunk_size: Some(*chunk_size),
+                ..Default::default()
+            }).unwrap();
+            let mut req = Request::new(Method::Post, format!("{}/new", prefix).parse().unwrap());
+            req.headers_mut().set(ContentLength(param.len() as u64));
+            req.set_body(param);
+            core.run({
+                let client = Client::new(handle);
+                client
+                    .request(req)
+                    .and_then(|res| check_error_response(res, "Invalid Parameter"))
+            }).unwrap();
+        }
+
+        let param = serde_json::to_vec(&NewRequest {
+            chunk_size: Some(4096),
+            ..Default::default()
+        }).unwrap();
+        let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
+        let payload = vec![1u8; 4096 * 3 + 100];
+
+        assert_eq!(
+            req_push(prefix, flow_id, token, &payload),
+            (StatusCode::Ok, None)
+        );
+        assert_eq!(
+            req_status(prefix, flow_id),
+            (
+                StatusCode::Ok,
+                Some(StatusResponse {
+                    tail: 0,
+                    next: 4,
+                    dropped: 0,
+                    pushed: payload.len() as u64,
+                    lifetime: Some(6),
+                    ..Default::default()
                 }),
             )
         );
+        for idx in 0..3 {
+            assert_eq!(
+                req_fetch(prefix, flow_id, idx),
+                (StatusCode::Ok, Some(payload[..4096].to_vec()))
+            );
+        }
+        assert_eq!(
+            req_fetch(prefix, flow_id, 3),
+            (StatusCode::Ok, Some(payload[..100].to_vec()))
+        );
+    }
+
+    #[test]
+    fn flow_timeouts() {
+        let prefix = &spawn_server();
+        let mut core = Core::new().unwrap();
+        let handle = &core.handle();
+
+        for &(ttl, idle_timeout) in [(Some(0), None), (None, Some(0)), (None, Some(7))].iter() {
+            let param = serde_json::to_string(&NewRequest {
+                ttl,
+                idle_timeout,
+                ..Default::default()
+            }).unwrap();
+            let mut req = Request::new(Method::Post, format!("{}/new", prefix).parse().unwrap());
+            req.headers_mut().set(ContentLength(param.len() as u64));
+            req.set_body(param);
+            core.run({
+                let client = Client::new(handle);
+                client
+                    .request(req)
+                    .and_then(|res| check_error_response(res, "Invalid Parameter"))
+            }).unwrap();
+        }
+
+        let param = serde_json::to_vec(&NewRequest {
+            ttl: Some(2),
+            idle_timeout: Some(4),
+            ..Default::default()
+        }).unwrap();
+        let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
+        assert_eq!(
+            req_status(prefix, flow_id).1.unwrap().lifetime,
+            Some(2)
+        );
+        assert_eq!(
+            req_push(prefix, flow_id, token, b"Hello"),
+            (StatusCode::Ok, None)
+        );
+
+        thread::sleep(Duration::from_millis(3500));
+        assert_eq!(req_status(prefix, flow_id), (StatusCode::NotFound, None));
     }
 
     #[test]
@@ -1686,7 +3538,7 @@ mod tests {
 
         let param = serde_json::to_vec(&NewRequest {
             size: Some(5),
-            preserve_mode: false,
+            ..Default::default()
         }).unwrap();
         let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
 
@@ -1725,7 +3577,7 @@ mod tests {
 
         let param = serde_json::to_vec(&NewRequest {
             size: Some(0),
-            preserve_mode: false,
+            ..Default::default()
         }).unwrap();
         let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
 
@@ -1736,6 +3588,45 @@ mod tests {
         assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
     }
 
+    #[test]
+    fn rate_limits() {
+        let prefix = &spawn_server();
+        let mut core = Core::new().unwrap();
+        let handle = &core.handle();
+
+        let mut req = Request::new(Method::Post, format!("{}/new", prefix).parse().unwrap());
+        let param = r#"{"preserve_mode": false, "push_rate": 0}"#;
+        req.set_body(param);
+        req.headers_mut().set(ContentLength(param.len() as u64));
+        core.run({
+            let client = Client::new(handle);
+            client
+                .request(req)
+                .and_then(|res| check_error_response(res, "Invalid Parameter"))
+        }).unwrap();
+
+        let param = serde_json::to_vec(&NewRequest {
+            push_rate: Some(65536),
+            pull_rate: Some(65536),
+            ..Default::default()
+        }).unwrap();
+        let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
+
+        // A second of data passes at once, the rest at the rate.
+        let payload = vec![1u8; 65536 * 3];
+        let start = Instant::now();
+        assert_eq!(
+            req_push(prefix, flow_id, token, &payload),
+            (StatusCode::Ok, None)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+        assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+
+        let start = Instant::now();
+        assert_eq!(req_pull(prefix, flow_id), (StatusCode::Ok, Some(payload)));
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+    }
+
     struct HttpsConnector {
         tls: Arc<TlsConnector>,
         http: HttpConnector,
@@ -1760,20 +3651,32 @@ mod tests {
     #[test]
     fn tls_service() {
         #[cfg(target_os = "windows")]
-        let tls_acceptor = tls::build_tls_from_pfx("./tests/cert.p12");
+        let tls_source = TlsSource::Pfx {
+            pfx_path: "./tests/cert.p12".into(),
+        };
         #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
-        let tls_acceptor = tls::build_tls_from_pem("./tests/cert.pem", "./tests/private.pem");
-        let (bind_addr, _) = start_service(
-            "127.0.0.1:0".parse().unwrap(),
-            1,
-            Some(32),
-            Some(Duration::from_secs(6)),
-            MAX_CAPACITY,
-            MAX_CAPACITY,
+        let tls_source = TlsSource::Pem {
+            cert_path: "./tests/cert.pem".into(),
+            priv_path: "./tests/private.pem".into(),
+            sni: Default::default(),
+            client_ca: None,
+        };
+        let tls_acceptor = SharedAcceptor::new(tls_source).unwrap();
+        let service = start_service(
+            vec![("127.0.0.1:0".parse().unwrap(), false)],
+            ServiceOptions {
+                deactive_timeout: Some(Duration::from_secs(6)),
+                ..options()
+            },
+            Pool::new(4, Some(32), Some(Duration::from_secs(6))),
+            Arc::new(HMACAuthorizer::new()),
+            Limits::new(0, 0, None, None, 0, 0),
+            Metrics::new(1),
+            Logger::new(Level::Error, Box::new(io::sink())),
             Some(tls_acceptor),
         );
 
-        let prefix = format!("https://127.0.0.1:{}", bind_addr.port());
+        let prefix = format!("https://127.0.0.1:{}", tcp_addr(&service).port());
         let mut core = Core::new().unwrap();
 
         let rootca = {
@@ -1820,14 +3723,147 @@ mod tests {
     }
 
     #[test]
-    fn multi_workers() {
-        start_service(
-            "127.0.0.1:0".parse().unwrap(),
-            4,
+    fn worker_balance() {
+        assert_eq!(worker_order(&[0, 0, 0, 0], 0), vec![0, 1, 2, 3]);
+        assert_eq!(worker_order(&[0, 0, 0, 0], 2), vec![2, 3, 0, 1]);
+        assert_eq!(worker_order(&[3, 1, 0, 1], 0), vec![2, 1, 3, 0]);
+        assert_eq!(worker_order(&[3, 1, 0, 1], 2), vec![2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn connection_limits() {
+        let service = start_service(
+            vec![("127.0.0.1:0".parse().unwrap(), false)],
+            options(),
+            Pool::new(4, None, None),
+            Arc::new(HMACAuthorizer::new()),
+            Limits::new(
+                0,
+                1,
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(3)),
+                0,
+                0,
+            ),
+            Metrics::new(1),
+            Logger::new(Level::Error, Box::new(io::sink())),
             None,
+        );
+        let connect = || {
+            let conn = std::net::TcpStream::connect(tcp_addr(&service)).unwrap();
+            conn.set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            conn
+        };
+        let mut buf = [0u8; 16];
+
+        // The second connection from the same address is refused.
+        let mut silent_conn = connect();
+        assert_eq!(connect().read(&mut buf).unwrap(), 0);
+        // Then the silent one is closed by the header timeout, well before the idle timeout.
+        let start = Instant::now();
+        assert_eq!(silent_conn.read(&mut buf).unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        // A request head which never completes is closed early.
+        let mut slow_conn = connect();
+        slow_conn.write_all(b"GET /healthz HTTP/1.1\r\n").unwrap();
+        let start = Instant::now();
+        assert_eq!(slow_conn.read(&mut buf).unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn proxy_protocol() {
+        fn probe<T: Read + Write>(conn: &mut T) -> String {
+            conn.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut buf = [0u8; 12];
+            conn.read_exact(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf).into_owned()
+        }
+        fn is_closed(conn: &mut std::net::TcpStream) -> bool {
+            conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            conn.read(&mut [0u8; 1]).unwrap_or(0) == 0
+        }
+
+        let socket_path = env::temp_dir().join(format!("furakus-{}.sock", process::id()));
+        let mut listeners = vec![("127.0.0.1:0".parse().unwrap(), true)];
+        if cfg!(unix) {
+            listeners.push((ListenAddr::Unix(socket_path.clone()), false));
+        }
+        let service = start_service(
+            listeners,
+            options(),
+            Pool::new(4, Some(32), None),
+            Arc::new(HMACAuthorizer::new()),
+            Limits::new(3, 1, None, None, 0, 0),
+            Metrics::new(1),
+            Logger::new(Level::Error, Box::new(io::sink())),
             None,
-            MAX_CAPACITY,
-            MAX_CAPACITY,
+        );
+        let addr = tcp_addr(&service);
+
+        let mut conn1 = std::net::TcpStream::connect(addr).unwrap();
+        conn1
+            .write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 56324 443\r\n")
+            .unwrap();
+        assert_eq!(probe(&mut conn1), "HTTP/1.1 200");
+
+        // The per-IP limit sees the client behind the proxy.
+        let mut conn2 = std::net::TcpStream::connect(addr).unwrap();
+        conn2.write_all(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c").unwrap();
+        conn2
+            .write_all(&[192, 0, 2, 1, 127, 0, 0, 1, 0xdc, 0x05, 0x01, 0xbb])
+            .unwrap();
+        assert!(is_closed(&mut conn2));
+        let mut conn3 = std::net::TcpStream::connect(addr).unwrap();
+        conn3
+            .write_all(b"PROXY TCP4 192.0.2.2 127.0.0.1 56324 443\r\n")
+            .unwrap();
+        assert_eq!(probe(&mut conn3), "HTTP/1.1 200");
+
+        // The header is required on the listener.
+        let mut conn4 = std::net::TcpStream::connect(addr).unwrap();
+        conn4
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert!(is_closed(&mut conn4));
+
+        // A client stalling the header holds a connection slot from the accept.
+        let silent_conn = std::net::TcpStream::connect(addr).unwrap();
+        let mut conn5 = std::net::TcpStream::connect(addr).unwrap();
+        conn5
+            .write_all(b"PROXY TCP4 192.0.2.3 127.0.0.1 56324 443\r\n")
+            .unwrap();
+        assert!(is_closed(&mut conn5));
+        drop(silent_conn);
+        // Let the worker notice the close and give the slot back.
+        thread::sleep(Duration::from_millis(200));
+
+        #[cfg(unix)]
+        {
+            let mut conn = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+            assert_eq!(probe(&mut conn), "HTTP/1.1 200");
+        }
+        drop((conn1, conn3));
+        service.shutdown(Duration::from_secs(1));
+        assert!(!socket_path.exists());
+    }
+
+    #[test]
+    fn multi_workers() {
+        start_service(
+            vec![("127.0.0.1:0".parse().unwrap(), false)],
+            ServiceOptions {
+                num_worker: 4,
+                ..options()
+            },
+            Pool::new(4, None, None),
+            Arc::new(HMACAuthorizer::new()),
+            Limits::new(0, 0, None, None, 0, 0),
+            Metrics::new(4),
+            Logger::new(Level::Error, Box::new(io::sink())),
             None,
         );
     }
@@ -1841,6 +3877,7 @@ mod tests {
         let param = serde_json::to_vec(&NewRequest {
             size: Some(MAX_CAPACITY * 4),
             preserve_mode: true,
+            ..Default::default()
         }).unwrap();
         let (ref flow_id, ref token) = create_flow(prefix, &String::from_utf8(param).unwrap());
 
@@ -1852,8 +3889,8 @@ mod tests {
                 let prefix = &prefix;
                 let flow_id = &flow_id;
                 let token = &token;
-                let payload = vec![0u8; flow::REF_SIZE];
-                for _ in 0..(MAX_CAPACITY * 2) / flow::REF_SIZE as u64 {
+                let payload = vec![0u8; flow::DEFAULT_CHUNK_SIZE];
+                for _ in 0..(MAX_CAPACITY * 2) / flow::DEFAULT_CHUNK_SIZE as u64 {
                     assert_eq!(
                         req_push(prefix, flow_id, token, &payload),
                         (StatusCode::Ok, None)
@@ -1896,8 +3933,8 @@ mod tests {
                 let prefix = &prefix;
                 let flow_id = &flow_id;
                 let token = &token;
-                let payload = vec![1u8; flow::REF_SIZE];
-                for _ in 0..(MAX_CAPACITY * 2) / flow::REF_SIZE as u64 {
+                let payload = vec![1u8; flow::DEFAULT_CHUNK_SIZE];
+                for _ in 0..(MAX_CAPACITY * 2) / flow::DEFAULT_CHUNK_SIZE as u64 {
                     assert_eq!(
                         req_push(prefix, flow_id, token, &payload),
                         (StatusCode::Ok, None)
@@ -2010,7 +4047,7 @@ mod tests {
         };
 
         let (ref flow_id, ref token) = create_flow(prefix, &DEFL_FLOW_PARAM);
-        let payload = vec![1u8; flow::REF_SIZE];
+        let payload = vec![1u8; flow::DEFAULT_CHUNK_SIZE];
 
         let thd = {
             let flow_id = flow_id.to_owned();
@@ -2051,4 +4088,51 @@ mod tests {
 
         thd.join().unwrap();
     }
+
+    #[test]
+    fn http2_prior_knowledge() {
+        let prefix = &spawn_server();
+        let (ref flow_id, ref token) = create_flow(prefix, DEFL_FLOW_PARAM);
+        assert_eq!(req_push(prefix, flow_id, token, b"Hello"), (StatusCode::Ok, None));
+        assert_eq!(req_close(prefix, flow_id, token), (StatusCode::Ok, None));
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = prefix.trim_left_matches("http://").parse().unwrap();
+        let (mut client, connection) = core.run(
+            TcpStream::connect(&addr, &handle)
+                .map_err(h2::Error::from)
+                .and_then(|io| h2::client::handshake(io)),
+        ).unwrap();
+        handle.spawn(connection.map_err(|_| ()));
+
+        // Both requests share the same connection.
+        let mut responses = Vec::new();
+        let requests = [
+            (http::Method::GET, format!("{}/flow/{}/fetch/0", prefix, flow_id)),
+            (http::Method::POST, format!("{}/flow/{}/status", prefix, flow_id)),
+        ];
+        for &(ref method, ref uri) in requests.iter() {
+            core.run(future::poll_fn(|| client.poll_ready())).unwrap();
+            let req = http::Request::builder()
+                .method(method.clone())
+                .uri(uri.as_str())
+                .body(())
+                .unwrap();
+            let (response, _) = client.send_request(req, true).unwrap();
+            responses.push(response);
+        }
+        let responses = core.run(future::join_all(responses.into_iter().map(|response| {
+            response.and_then(|res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map(move |body| (status, body.to_vec()))
+            })
+        }))).unwrap();
+        assert_eq!(responses[0], (http::StatusCode::OK, b"Hello".to_vec()));
+        assert_eq!(responses[1].0, http::StatusCode::OK);
+        let status = serde_json::from_slice::<StatusResponse>(&responses[1].1).unwrap();
+        assert_eq!(status.pushed, 5);
+    }
 }
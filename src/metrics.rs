@@ -0,0 +1,347 @@
+use flow::{Flow, Observer};
+use limits::{Violation, VIOLATIONS};
+use pool::Pool;
+use std::{fmt::Write, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::Duration};
+
+const LATENCY_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    New,
+    Push,
+    Eof,
+    Status,
+    Fetch,
+    Pull,
+    Chunks,
+    Read,
+    Options,
+    Unknown,
+}
+
+const ROUTES: [Route; 10] = [
+    Route::New,
+    Route::Push,
+    Route::Eof,
+    Route::Status,
+    Route::Fetch,
+    Route::Pull,
+    Route::Chunks,
+    Route::Read,
+    Route::Options,
+    Route::Unknown,
+];
+
+impl Route {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Route::New => "new",
+            Route::Push => "push",
+            Route::Eof => "eof",
+            Route::Status => "status",
+            Route::Fetch => "fetch",
+            Route::Pull => "pull",
+            Route::Chunks => "chunks",
+            Route::Read => "read",
+            Route::Options => "options",
+            Route::Unknown => "unknown",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    sum_micros: AtomicUsize,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+            sum_micros: AtomicUsize::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros =
+            elapsed.as_secs() as usize * 1000000 + elapsed.subsec_nanos() as usize / 1000;
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    bytes_pushed: AtomicUsize,
+    bytes_pulled: AtomicUsize,
+    bytes_dropped: AtomicUsize,
+    flows_created: AtomicUsize,
+    flows_closed: AtomicUsize,
+    pushes_blocked: AtomicUsize,
+    requests: Vec<Histogram>,
+    connections: Vec<AtomicUsize>,
+    connections_dropped: Vec<AtomicUsize>,
+}
+
+impl Metrics {
+    pub fn new(num_worker: usize) -> Arc<Self> {
+        Arc::new(Metrics {
+            bytes_pushed: AtomicUsize::new(0),
+            bytes_pulled: AtomicUsize::new(0),
+            bytes_dropped: AtomicUsize::new(0),
+            flows_created: AtomicUsize::new(0),
+            flows_closed: AtomicUsize::new(0),
+            pushes_blocked: AtomicUsize::new(0),
+            requests: ROUTES.iter().map(|_| Histogram::new()).collect(),
+            connections: (0..num_worker).map(|_| AtomicUsize::new(0)).collect(),
+            connections_dropped: VIOLATIONS.iter().map(|_| AtomicUsize::new(0)).collect(),
+        })
+    }
+
+    pub fn add_created(&self) {
+        self.flows_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_pulled(&self, len: u64) {
+        self.bytes_pulled.fetch_add(len as usize, Ordering::Relaxed);
+    }
+
+    pub fn observe_request(&self, route: Route, elapsed: Duration) {
+        self.requests[route as usize].observe(elapsed);
+    }
+
+    pub fn connect(&self, worker: usize) {
+        self.connections[worker].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnect(&self, worker: usize) {
+        self.connections[worker].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dropped(&self, violation: Violation) {
+        self.connections_dropped[violation as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_connections(&self, worker: usize) -> usize {
+        self.connections[worker].load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self, pool: &Pool) -> String {
+        let mut output = String::new();
+        {
+            let mut metric = |name: &str, kind: &str, help: &str, value: usize| {
+                writeln!(output, "# HELP furakus_{} {}", name, help).unwrap();
+                writeln!(output, "# TYPE furakus_{} {}", name, kind).unwrap();
+                writeln!(output, "furakus_{} {}", name, value).unwrap();
+            };
+            metric(
+                "bytes_pushed_total",
+                "counter",
+                "Bytes pushed into flows.",
+                self.bytes_pushed.load(Ordering::Relaxed),
+            );
+            metric(
+                "bytes_pulled_total",
+                "counter",
+                "Bytes sent by fetches and pulls.",
+                self.bytes_pulled.load(Ordering::Relaxed),
+            );
+            metric(
+                "bytes_dropped_total",
+                "counter",
+                "Bytes dropped from flow buffers.",
+                self.bytes_dropped.load(Ordering::Relaxed),
+            );
+            metric(
+                "flows_created_total",
+                "counter",
+                "Flows created.",
+                self.flows_created.load(Ordering::Relaxed),
+            );
+            metric(
+                "flows_closed_total",
+                "counter",
+                "Flows closed.",
+                self.flows_closed.load(Ordering::Relaxed),
+            );
+            metric(
+                "flows_evicted_total",
+                "counter",
+                "Flows evicted from the pool.",
+                pool.get_evicted(),
+            );
+            metric(
+                "pushes_blocked_total",
+                "counter",
+                "Pushes blocked on flow overflow.",
+                self.pushes_blocked.load(Ordering::Relaxed),
+            );
+
+            let usage = pool.get_usage();
+            metric(
+                "pushes_waiting",
+                "gauge",
+                "Pushes currently waiting for flow space.",
+                usage.waiting_pushes as usize,
+            );
+            metric(
+                "pulls_waiting",
+                "gauge",
+                "Pulls currently waiting for data.",
+                usage.waiting_pulls as usize,
+            );
+            metric("pool_flows", "gauge", "Flows in the pool.", pool.len());
+            if let Some(pool_size) = pool.get_pool_size() {
+                metric("pool_size", "gauge", "Capacity of the pool.", pool_size);
+            }
+        }
+
+        writeln!(
+            output,
+            "# HELP furakus_connections_active Active connections per worker."
+        ).unwrap();
+        writeln!(output, "# TYPE furakus_connections_active gauge").unwrap();
+        for (idx, connections) in self.connections.iter().enumerate() {
+            writeln!(
+                output,
+                "furakus_connections_active{{worker=\"{}\"}} {}",
+                idx,
+                connections.load(Ordering::Relaxed)
+            ).unwrap();
+        }
+
+        writeln!(
+            output,
+            "# HELP furakus_connections_dropped_total Connections dropped by the limits."
+        ).unwrap();
+        writeln!(output, "# TYPE furakus_connections_dropped_total counter").unwrap();
+        for (violation, dropped) in VIOLATIONS.iter().zip(self.connections_dropped.iter()) {
+            writeln!(
+                output,
+                "furakus_connections_dropped_total{{reason=\"{}\"}} {}",
+                violation.name(),
+                dropped.load(Ordering::Relaxed)
+            ).unwrap();
+        }
+
+        writeln!(
+            output,
+            "# HELP furakus_request_duration_seconds Request latency per route."
+        ).unwrap();
+        writeln!(output, "# TYPE furakus_request_duration_seconds histogram").unwrap();
+        for (route, histogram) in ROUTES.iter().zip(self.requests.iter()) {
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(
+                    output,
+                    "furakus_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route.name(),
+                    bound,
+                    bucket.load(Ordering::Relaxed)
+                ).unwrap();
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            writeln!(
+                output,
+                "furakus_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                route.name(),
+                count
+            ).unwrap();
+            writeln!(
+                output,
+                "furakus_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route.name(),
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+            ).unwrap();
+            writeln!(
+                output,
+                "furakus_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route.name(),
+                count
+            ).unwrap();
+        }
+        output
+    }
+}
+
+impl Observer for Arc<Metrics> {
+    fn on_close(&self, _flow: &Flow) {
+        self.flows_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_push(&self, _flow: &Flow, len: u64) {
+        self.bytes_pushed.fetch_add(len as usize, Ordering::Relaxed);
+    }
+
+    fn on_drop(&self, _flow: &Flow, len: u64) {
+        self.bytes_dropped.fetch_add(len as usize, Ordering::Relaxed);
+    }
+
+    fn on_block(&self, _flow: &Flow) {
+        self.pushes_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flow::{self, test_config};
+    use futures::Future;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new(2);
+        let pool_ptr = Pool::new(4, Some(16), None);
+        let flow_ptr = Flow::new(flow::Config {
+            data_capacity: 8,
+            ..test_config()
+        });
+        flow_ptr.write().unwrap().observe(metrics.clone());
+        pool_ptr.insert(flow_ptr.clone()).unwrap();
+        metrics.add_created();
+
+        flow_ptr.write().unwrap().push("Hello".into());
+        let push_fut = flow_ptr.write().unwrap().push("World".into());
+        let _pull_fut = flow_ptr.read().unwrap().pull(2, None);
+        let fut = flow_ptr.read().unwrap().pull(0, Some(0));
+        metrics.add_pulled(fut.wait().unwrap().len() as u64);
+        assert_eq!(push_fut.wait(), Ok(1));
+        metrics.observe_request(Route::Fetch, Duration::from_millis(20));
+        metrics.connect(1);
+        metrics.add_dropped(Violation::IdleTimeout);
+
+        let output = metrics.render(&pool_ptr);
+        let expected = [
+            "furakus_bytes_pushed_total 10",
+            "furakus_bytes_pulled_total 5",
+            "furakus_bytes_dropped_total 5",
+            "furakus_flows_created_total 1",
+            "furakus_flows_closed_total 0",
+            "furakus_flows_evicted_total 0",
+            "furakus_pushes_blocked_total 1",
+            "furakus_pushes_waiting 0",
+            "furakus_pulls_waiting 1",
+            "furakus_pool_flows 1",
+            "furakus_pool_size 16",
+            "furakus_connections_active{worker=\"0\"} 0",
+            "furakus_connections_active{worker=\"1\"} 1",
+            "furakus_connections_dropped_total{reason=\"idle_timeout\"} 1",
+            "furakus_connections_dropped_total{reason=\"too_slow\"} 0",
+            "furakus_request_duration_seconds_bucket{route=\"fetch\",le=\"0.01\"} 0",
+            "furakus_request_duration_seconds_bucket{route=\"fetch\",le=\"0.05\"} 1",
+            "furakus_request_duration_seconds_bucket{route=\"fetch\",le=\"+Inf\"} 1",
+            "furakus_request_duration_seconds_sum{route=\"fetch\"} 0.02",
+            "furakus_request_duration_seconds_count{route=\"fetch\"} 1",
+            "furakus_request_duration_seconds_count{route=\"new\"} 0",
+        ];
+        for line in expected.iter() {
+            assert!(output.lines().any(|output_line| output_line == *line), "{}", line);
+        }
+    }
+}
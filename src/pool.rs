@@ -1,185 +1,367 @@
-use flow::{Flow, Observer};
-use std::{iter, collections::HashMap, sync::{Arc, Mutex, RwLock, Weak}, time::{Duration, Instant}};
+use flow::{Flow, Observer, Usage};
+use std::{collections::{BTreeMap, BTreeSet, hash_map::DefaultHasher}, hash::{Hash, Hasher},
+          ops::Bound, sync::{Arc, Mutex, RwLock, Weak, atomic::{AtomicUsize, Ordering}},
+          time::{Duration, Instant}};
 
 pub type SharedFlow = Arc<RwLock<Flow>>;
 
-type LinkPointer<T> = Weak<Mutex<T>>;
-
-struct TimeLinkNode {
-    key: String,
+struct Entry {
+    flow: SharedFlow,
     timestamp: Instant,
-    prev: Option<LinkPointer<TimeLinkNode>>,
-    next: Option<LinkPointer<TimeLinkNode>>,
+    idle_timeout: Option<Duration>,
+    expire_at: Option<Instant>,
+    // The position in the deadline index of the shard, None means never.
+    deadline: Option<Instant>,
+    // The usage last reported by the flow, which is counted in the totals.
+    usage: Mutex<Usage>,
 }
 
-struct Entry {
-    flow: SharedFlow,
-    node: Arc<Mutex<TimeLinkNode>>,
+// The usage of all the flows in the pool, kept up to date by the flows.
+#[derive(Default)]
+struct Totals {
+    buffered_bytes: AtomicUsize,
+    buffered_chunks: AtomicUsize,
+    waiting_pushes: AtomicUsize,
+    waiting_pulls: AtomicUsize,
+}
+
+struct Shard {
+    weakref: Weak<RwLock<Shard>>,
+    // Sorted by flow id for listing.
+    bucket: BTreeMap<String, Entry>,
+    // Keep the flows sorted by deadline, the earliest one first.
+    deadlines: BTreeSet<(Instant, String)>,
+    population: Arc<AtomicUsize>,
+    totals: Arc<Totals>,
+    deactive_timeout: Option<Duration>,
 }
 
 pub struct Pool {
-    weakref: Weak<RwLock<Pool>>,
-    bucket: HashMap<String, Entry>,
+    shards: Vec<Arc<RwLock<Shard>>>,
+    population: Arc<AtomicUsize>,
+    totals: Arc<Totals>,
+    evicted: AtomicUsize,
     pool_size: Option<usize>,
-    deactive_timeout: Option<Duration>,
-    timelink_head: Option<LinkPointer<TimeLinkNode>>,
-    timelink_tail: Option<LinkPointer<TimeLinkNode>>,
 }
 
 impl Entry {
-    fn new(flow_id: &str, flow_ptr: SharedFlow) -> Self {
-        Entry {
+    fn new(
+        flow_ptr: SharedFlow,
+        idle_timeout: Option<Duration>,
+        expire_at: Option<Instant>,
+    ) -> Self {
+        let mut entry = Entry {
             flow: flow_ptr,
-            node: Arc::new(Mutex::new(TimeLinkNode {
-                key: flow_id.to_owned(),
-                timestamp: Instant::now(),
-                prev: Some(Weak::new()),
-                next: Some(Weak::new()),
-            })),
+            timestamp: Instant::now(),
+            idle_timeout,
+            expire_at,
+            deadline: None,
+            usage: Mutex::new(Usage::default()),
+        };
+        entry.deadline = entry.get_deadline();
+        entry
+    }
+
+    fn get_deadline(&self) -> Option<Instant> {
+        let idle_deadline = self.idle_timeout.map(|timeout| self.timestamp + timeout);
+        match (idle_deadline, self.expire_at) {
+            (Some(idle_deadline), Some(expire_at)) => Some(idle_deadline.min(expire_at)),
+            (idle_deadline, None) => idle_deadline,
+            (None, expire_at) => expire_at,
         }
     }
 }
 
-impl TimeLinkNode {
-    fn push(node: &mut TimeLinkNode, node_ptr: &Arc<Mutex<TimeLinkNode>>, pool: &mut Pool) {
-        node.prev = None;
-        node.next = pool.timelink_head.clone();
-        let weakref = Arc::downgrade(node_ptr);
-        if let Some(ref head) = pool.timelink_head {
-            let head = head.upgrade().unwrap();
-            head.lock().unwrap().prev = Some(weakref.clone());
+impl Totals {
+    fn update(counter: &AtomicUsize, old: u64, new: u64) {
+        if new > old {
+            counter.fetch_add((new - old) as usize, Ordering::SeqCst);
+        } else if new < old {
+            counter.fetch_sub((old - new) as usize, Ordering::SeqCst);
         }
-        pool.timelink_head = Some(weakref);
     }
 
-    fn unlink(node: &mut TimeLinkNode, pool: &mut Pool) {
-        if let Some(ref prev) = node.prev {
-            // Upgrade should always success.
-            let prev = prev.upgrade().unwrap();
-            prev.lock().unwrap().next = node.next.clone();
-        } else {
-            pool.timelink_head = node.next.clone();
+    fn replace(&self, old: &Usage, new: &Usage) {
+        Self::update(&self.buffered_bytes, old.buffered_bytes, new.buffered_bytes);
+        Self::update(&self.buffered_chunks, old.buffered_chunks, new.buffered_chunks);
+        Self::update(&self.waiting_pushes, old.waiting_pushes, new.waiting_pushes);
+        Self::update(&self.waiting_pulls, old.waiting_pulls, new.waiting_pulls);
+    }
+
+    fn get(&self) -> Usage {
+        Usage {
+            buffered_bytes: self.buffered_bytes.load(Ordering::SeqCst) as u64,
+            buffered_chunks: self.buffered_chunks.load(Ordering::SeqCst) as u64,
+            waiting_pushes: self.waiting_pushes.load(Ordering::SeqCst) as u64,
+            waiting_pulls: self.waiting_pulls.load(Ordering::SeqCst) as u64,
         }
-        if let Some(ref next) = node.next {
-            // Upgrade should always success.
-            let next = next.upgrade().unwrap();
-            next.lock().unwrap().prev = node.prev.clone();
-        } else {
-            pool.timelink_tail = node.prev.clone();
+    }
+}
+
+impl Shard {
+    fn new(
+        population: Arc<AtomicUsize>,
+        totals: Arc<Totals>,
+        deactive_timeout: Option<Duration>,
+    ) -> Arc<RwLock<Self>> {
+        let shard = Shard {
+            weakref: Weak::new(),
+            bucket: BTreeMap::new(),
+            deadlines: BTreeSet::new(),
+            population,
+            totals,
+            deactive_timeout,
+        };
+        let shard_ptr = Arc::new(RwLock::new(shard));
+        shard_ptr.write().unwrap().weakref = Arc::downgrade(&shard_ptr);
+        shard_ptr
+    }
+
+    fn insert(&mut self, flow_ptr: SharedFlow) {
+        // Occupy the flow to prevent from race condition.
+        let mut flow = flow_ptr.write().unwrap();
+        let entry = {
+            let config = flow.get_config();
+            let idle_timeout = config
+                .idle_timeout
+                .map(|idle_timeout| Duration::from_secs(idle_timeout))
+                .or(self.deactive_timeout);
+            let expire_at = config
+                .ttl
+                .map(|ttl| flow.get_created() + Duration::from_secs(ttl));
+            Entry::new(flow_ptr.clone(), idle_timeout, expire_at)
+        };
+        if let Some(deadline) = entry.deadline {
+            self.deadlines.insert((deadline, flow.id.to_owned()));
+        }
+        let usage = flow.get_usage();
+        self.totals.replace(&Usage::default(), &usage);
+        *entry.usage.lock().unwrap() = usage;
+        if let Some(entry) = self.bucket.insert(flow.id.to_owned(), entry) {
+            self.release(&flow.id, entry);
+        }
+        flow.observe(self.weakref.clone());
+    }
+
+    fn release(&mut self, flow_id: &str, entry: Entry) {
+        if let Some(deadline) = entry.deadline {
+            self.deadlines.remove(&(deadline, flow_id.to_owned()));
+        }
+        self.totals.replace(&entry.usage.lock().unwrap(), &Usage::default());
+    }
+
+    fn get(&self, flow_id: &str) -> Option<SharedFlow> {
+        self.bucket.get(flow_id).map(|entry| entry.flow.clone())
+    }
+
+    fn touch(&mut self, flow_id: &str) {
+        let deadlines = &mut self.deadlines;
+        if let Some(entry) = self.bucket.get_mut(flow_id) {
+            entry.timestamp = Instant::now();
+            let deadline = entry.get_deadline();
+            if deadline == entry.deadline {
+                return;
+            }
+            if let Some(deadline) = entry.deadline {
+                deadlines.remove(&(deadline, flow_id.to_owned()));
+            }
+            if let Some(deadline) = deadline {
+                deadlines.insert((deadline, flow_id.to_owned()));
+            }
+            entry.deadline = deadline;
         }
-        node.prev = Some(Weak::new());
-        node.next = Some(Weak::new());
+    }
+
+    fn remove(&mut self, flow_id: &str) -> Result<(), ()> {
+        self.bucket.remove(flow_id).ok_or(()).and_then(|entry| {
+            self.release(flow_id, entry);
+            self.population.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+    }
+
+    fn sanitize_bucket(&mut self) -> Vec<SharedFlow> {
+        let now = Instant::now();
+        let droplist = self
+            .deadlines
+            .iter()
+            .take_while(|&&(deadline, _)| deadline <= now)
+            .map(|&(_, ref key)| key.to_owned())
+            .collect::<Vec<String>>();
+
+        droplist
+            .iter()
+            .filter_map(|key| {
+                let flow_ptr = self.get(key);
+                // Try to remove dead flows.
+                self.remove(key).ok().and(flow_ptr)
+            })
+            .collect()
     }
 }
 
 impl Pool {
-    pub fn new(pool_size: Option<usize>, deactive_timeout: Option<Duration>) -> Arc<RwLock<Self>> {
-        let pool = Pool {
-            weakref: Weak::new(),
-            bucket: HashMap::new(),
+    pub fn new(
+        num_shard: usize,
+        pool_size: Option<usize>,
+        deactive_timeout: Option<Duration>,
+    ) -> Arc<Self> {
+        let population = Arc::new(AtomicUsize::new(0));
+        let totals = Arc::new(Totals::default());
+        let shards = (0..num_shard.max(1))
+            .map(|_| Shard::new(population.clone(), totals.clone(), deactive_timeout))
+            .collect();
+        Arc::new(Pool {
+            shards,
+            population,
+            totals,
+            evicted: AtomicUsize::new(0),
             pool_size,
-            deactive_timeout,
-            timelink_head: None,
-            timelink_tail: None,
+        })
+    }
+
+    fn get_shard(&self, flow_id: &str) -> &Arc<RwLock<Shard>> {
+        let mut hasher = DefaultHasher::new();
+        flow_id.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    fn reserve(&self) -> bool {
+        let pool_size = match self.pool_size {
+            Some(pool_size) => pool_size,
+            None => {
+                self.population.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
         };
-        let pool_ptr = Arc::new(RwLock::new(pool));
-        pool_ptr.write().unwrap().weakref = Arc::downgrade(&pool_ptr);
-        pool_ptr
-    }
-
-    pub fn insert(&mut self, flow_ptr: SharedFlow) -> Result<(), ()> {
-        if let Some(pool_size) = self.pool_size {
-            if self.bucket.len() >= pool_size {
-                self.sanitize_bucket();
-                if self.bucket.len() >= pool_size {
-                    return Err(());
-                }
+        let mut population = self.population.load(Ordering::SeqCst);
+        loop {
+            if population >= pool_size {
+                return false;
+            }
+            match self.population.compare_exchange(
+                population,
+                population + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(current) => population = current,
             }
         }
-        {
-            // Occupy the flow to prevent from race condition.
-            let mut flow = flow_ptr.write().unwrap();
-            let node_ptr = {
-                self.bucket
-                    .entry(flow.id.to_owned())
-                    .or_insert(Entry::new(&flow.id, flow_ptr.clone()))
-                    .node
-                    .clone()
-            };
-            {
-                let mut node = node_ptr.lock().unwrap();
-                TimeLinkNode::push(&mut node, &node_ptr, self);
+    }
+
+    pub fn insert(&self, flow_ptr: SharedFlow) -> Result<(), ()> {
+        if !self.reserve() {
+            self.sweep();
+            if !self.reserve() {
+                return Err(());
             }
-            flow.observe(self.weakref.clone());
         }
+        let flow_id = flow_ptr.read().unwrap().id.to_owned();
+        let mut shard = self.get_shard(&flow_id).write().unwrap();
+        shard.insert(flow_ptr);
         Ok(())
     }
 
     pub fn get(&self, flow_id: &str) -> Option<SharedFlow> {
-        self.bucket.get(flow_id).map(|entry| entry.flow.clone())
+        self.get_shard(flow_id).read().unwrap().get(flow_id)
     }
 
-    pub fn remove(&mut self, flow_id: &str) -> Result<(), ()> {
-        self.bucket.remove(flow_id).ok_or(()).and_then(|entry| {
-            let mut node = entry.node.lock().unwrap();
-            TimeLinkNode::unlink(&mut node, self);
-            Ok(())
-        })
+    pub fn remove(&self, flow_id: &str) -> Result<(), ()> {
+        self.get_shard(flow_id).write().unwrap().remove(flow_id)
     }
 
-    fn sanitize_bucket(&mut self) {
-        let deactive_timeout = match self.deactive_timeout {
-            Some(deactive_timeout) => deactive_timeout,
-            None => return,
+    pub fn evict(&self, flow_id: &str) -> Result<(), ()> {
+        let flow_ptr = {
+            let mut shard = self.get_shard(flow_id).write().unwrap();
+            let flow_ptr = shard.get(flow_id).ok_or(())?;
+            shard.remove(flow_id)?;
+            flow_ptr
         };
+        // Expire outside the shard lock, since closing the flow notifies the shard.
+        flow_ptr.write().unwrap().expire();
+        self.evicted.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
 
-        let droplist = iter::repeat(())
-            .scan(self.timelink_tail.clone(), |tail_weakref, _| {
-                tail_weakref
-                    .as_ref()
-                    .and_then(|weakref| weakref.upgrade())
-                    .and_then(|tail_ptr| {
-                        let node = tail_ptr.lock().unwrap();
-                        if node.timestamp.elapsed() <= deactive_timeout {
-                            None
-                        } else {
-                            *tail_weakref = node.prev.clone();
-                            Some(node.key.to_owned())
-                        }
-                    })
-            })
-            .collect::<Vec<String>>();
+    /// List the flows sorted by id, starting after the given one.
+    pub fn list(&self, after: Option<&str>, limit: usize) -> Vec<SharedFlow> {
+        let lower = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        // Each shard contributes at most a page, which is then merged.
+        let mut flows = Vec::new();
+        for shard_ptr in self.shards.iter() {
+            let shard = shard_ptr.read().unwrap();
+            flows.extend(
+                shard
+                    .bucket
+                    .range::<str, _>((lower, Bound::Unbounded))
+                    .take(limit)
+                    .map(|(key, entry)| (key.to_owned(), entry.flow.clone())),
+            );
+        }
+        flows.sort_by(|a, b| a.0.cmp(&b.0));
+        flows.into_iter().take(limit).map(|(_, flow)| flow).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.population.load(Ordering::SeqCst)
+    }
+
+    pub fn get_pool_size(&self) -> Option<usize> {
+        self.pool_size
+    }
+
+    /// The usage summed over all the flows in the pool.
+    pub fn get_usage(&self) -> Usage {
+        self.totals.get()
+    }
+
+    pub fn get_evicted(&self) -> usize {
+        self.evicted.load(Ordering::SeqCst)
+    }
 
-        for key in droplist.iter() {
-            // Try to remove dead flows.
-            self.remove(key).is_ok();
+    pub fn sweep(&self) -> usize {
+        let mut count = 0;
+        for shard_ptr in self.shards.iter() {
+            let droplist = shard_ptr.write().unwrap().sanitize_bucket();
+            count += droplist.len();
+            // Expire outside the shard lock, since closing the flow notifies the shard.
+            for flow_ptr in droplist {
+                flow_ptr.write().unwrap().expire();
+            }
         }
+        self.evicted.fetch_add(count, Ordering::SeqCst);
+        count
     }
 }
 
-impl Observer for Weak<RwLock<Pool>> {
+impl Observer for Weak<RwLock<Shard>> {
     fn on_active(&self, flow: &Flow) {
-        if let Some(pool_ptr) = self.upgrade() {
-            // TODO minimize the pool write lock.
-            let mut pool = pool_ptr.write().unwrap();
-            let node_ptr = match pool.bucket.get(&flow.id) {
-                Some(entry) => entry.node.clone(),
-                None => return,
-            };
-            {
-                let mut node = node_ptr.lock().unwrap();
-                node.timestamp = Instant::now();
-                TimeLinkNode::unlink(&mut node, &mut pool);
-                TimeLinkNode::push(&mut node, &node_ptr, &mut pool);
+        if let Some(shard_ptr) = self.upgrade() {
+            shard_ptr.write().unwrap().touch(&flow.id);
+        }
+    }
+
+    fn on_usage(&self, flow: &Flow, usage: &Usage) {
+        if let Some(shard_ptr) = self.upgrade() {
+            let shard = shard_ptr.read().unwrap();
+            if let Some(entry) = shard.bucket.get(&flow.id) {
+                let mut last_usage = entry.usage.lock().unwrap();
+                shard.totals.replace(&last_usage, usage);
+                *last_usage = usage.clone();
             }
         }
     }
 
     fn on_close(&self, flow: &Flow) {
-        if let Some(pool_ptr) = self.upgrade() {
-            let mut pool = pool_ptr.write().unwrap();
+        if let Some(shard_ptr) = self.upgrade() {
+            let mut shard = shard_ptr.write().unwrap();
             // Try to remove the flow.
-            pool.remove(&flow.id).is_ok();
+            shard.remove(&flow.id).is_ok();
         }
     }
 }
@@ -187,35 +369,28 @@ impl Observer for Weak<RwLock<Pool>> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use flow;
+    use flow::{self, test_config};
+    use futures::Future;
     use std::thread;
     use tokio::reactor::Core;
 
-    const FLOW_CONFIG: flow::Config = flow::Config {
-        length: None,
-        meta_capacity: 16777216,
-        data_capacity: 16777216,
-        keepcount: Some(1),
-        preserve_mode: false,
-    };
-
     #[test]
     fn basic_operations() {
-        let ptr = Pool::new(None, None);
-        let flow_a = Flow::new(FLOW_CONFIG);
-        let flow_b = Flow::new(FLOW_CONFIG);
-        let flow_c = Flow::new(FLOW_CONFIG);
+        let ptr = Pool::new(4, None, None);
+        let flow_a = Flow::new(test_config());
+        let flow_b = Flow::new(test_config());
+        let flow_c = Flow::new(test_config());
         let flowa_id = flow_a.read().unwrap().id.to_owned();
         let flowb_id = flow_b.read().unwrap().id.to_owned();
         let flowc_id = flow_c.read().unwrap().id.to_owned();
         {
-            let mut pool = ptr.write().unwrap();
+            let pool = &ptr;
             assert_eq!(pool.insert(flow_a.clone()), Ok(()));
             assert_eq!(pool.insert(flow_b.clone()), Ok(()));
             assert_eq!(pool.insert(flow_c.clone()), Ok(()));
         }
         {
-            let pool = ptr.read().unwrap();
+            let pool = &ptr;
             assert!(Arc::ptr_eq(&pool.get(&flowa_id).unwrap(), &flow_a));
             assert!(Arc::ptr_eq(&pool.get(&flowb_id).unwrap(), &flow_b));
             assert!(!Arc::ptr_eq(&pool.get(&flowa_id).unwrap(), &flow_b));
@@ -223,13 +398,13 @@ mod tests {
             assert!(pool.get("C").is_none());
         }
         {
-            let mut pool = ptr.write().unwrap();
+            let pool = &ptr;
             assert_eq!(pool.remove(&flowb_id), Ok(()));
             assert_eq!(pool.remove(&flowa_id), Ok(()));
             assert_eq!(pool.remove(&flowc_id), Ok(()));
         }
         {
-            let pool = ptr.read().unwrap();
+            let pool = &ptr;
             assert!(pool.get(&flowa_id).is_none());
             assert!(pool.get(&flowb_id).is_none());
             assert!(pool.get(&flowc_id).is_none());
@@ -239,11 +414,11 @@ mod tests {
     #[test]
     fn close_recycle() {
         let mut core = Core::new().unwrap();
-        let ptr = Pool::new(None, None);
-        let flow = Flow::new(FLOW_CONFIG);
+        let ptr = Pool::new(4, None, None);
+        let flow = Flow::new(test_config());
         let flow_id = flow.read().unwrap().id.to_owned();
         {
-            let mut pool = ptr.write().unwrap();
+            let pool = &ptr;
             pool.insert(flow.clone()).unwrap();
             assert!(Arc::ptr_eq(&pool.get(&flow_id).unwrap(), &flow));
         }
@@ -255,22 +430,22 @@ mod tests {
             let fut = flow.read().unwrap().pull(0, Some(0));
             core.run(fut).is_err();
         }
-        assert!(ptr.read().unwrap().get(&flow_id).is_none());
+        assert!(ptr.get(&flow_id).is_none());
     }
 
     #[test]
     fn dropped() {
         let mut core = Core::new().unwrap();
-        let flow = Flow::new(FLOW_CONFIG);
+        let flow = Flow::new(test_config());
         {
-            let ptr = Pool::new(None, None);
+            let ptr = Pool::new(4, None, None);
             let flow_id = flow.read().unwrap().id.to_owned();
             {
-                let mut pool = ptr.write().unwrap();
+                let pool = &ptr;
                 pool.insert(flow.clone()).unwrap();
                 pool.remove(&flow_id).unwrap();
             }
-            assert!(ptr.read().unwrap().get(&flow_id).is_none());
+            assert!(ptr.get(&flow_id).is_none());
             {
                 let fut = flow.write().unwrap().close();
                 core.run(fut).unwrap();
@@ -284,11 +459,11 @@ mod tests {
 
     #[test]
     fn overload_size() {
-        let ptr = Pool::new(Some(1), None);
-        let flow_a = Flow::new(FLOW_CONFIG);
-        let flow_b = Flow::new(FLOW_CONFIG);
+        let ptr = Pool::new(4, Some(1), None);
+        let flow_a = Flow::new(test_config());
+        let flow_b = Flow::new(test_config());
         {
-            let mut pool = ptr.write().unwrap();
+            let pool = &ptr;
             assert_eq!(pool.insert(flow_a.clone()), Ok(()));
             assert_eq!(pool.insert(flow_b.clone()), Err(()));
         }
@@ -297,15 +472,15 @@ mod tests {
     #[test]
     fn overload_time() {
         let mut core = Core::new().unwrap();
-        let ptr = Pool::new(Some(3), Some(Duration::from_secs(6)));
-        let flow_a = Flow::new(FLOW_CONFIG);
-        let flow_b = Flow::new(FLOW_CONFIG);
-        let flow_c = Flow::new(FLOW_CONFIG);
-        let flow_d = Flow::new(FLOW_CONFIG);
-        let flow_e = Flow::new(FLOW_CONFIG);
-        let flow_f = Flow::new(FLOW_CONFIG);
+        let ptr = Pool::new(4, Some(3), Some(Duration::from_secs(6)));
+        let flow_a = Flow::new(test_config());
+        let flow_b = Flow::new(test_config());
+        let flow_c = Flow::new(test_config());
+        let flow_d = Flow::new(test_config());
+        let flow_e = Flow::new(test_config());
+        let flow_f = Flow::new(test_config());
         {
-            let mut pool = ptr.write().unwrap();
+            let pool = &ptr;
             assert_eq!(pool.insert(flow_a.clone()), Ok(()));
             assert_eq!(pool.insert(flow_b.clone()), Ok(()));
             assert_eq!(pool.insert(flow_c.clone()), Ok(()));
@@ -317,10 +492,157 @@ mod tests {
         }
         thread::sleep(Duration::from_secs(4));
         {
-            let mut pool = ptr.write().unwrap();
+            let pool = &ptr;
             assert_eq!(pool.insert(flow_d.clone()), Ok(()));
             assert_eq!(pool.insert(flow_e.clone()), Ok(()));
             assert_eq!(pool.insert(flow_f.clone()), Err(()));
         }
     }
+
+    #[test]
+    fn sharding() {
+        let ptr = Pool::new(8, Some(64), None);
+        let flows: Vec<_> = (0..64).map(|_| Flow::new(test_config())).collect();
+        for flow in flows.iter() {
+            assert_eq!(ptr.insert(flow.clone()), Ok(()));
+        }
+        assert_eq!(ptr.insert(Flow::new(test_config())), Err(()));
+        for flow in flows.iter() {
+            let flow_id = flow.read().unwrap().id.to_owned();
+            assert!(Arc::ptr_eq(&ptr.get(&flow_id).unwrap(), flow));
+        }
+        let flow_id = flows[0].read().unwrap().id.to_owned();
+        assert_eq!(ptr.remove(&flow_id), Ok(()));
+        assert_eq!(ptr.remove(&flow_id), Err(()));
+        assert_eq!(ptr.insert(Flow::new(test_config())), Ok(()));
+    }
+
+    #[test]
+    fn list_and_evict() {
+        let ptr = Pool::new(4, Some(8), None);
+        let flows: Vec<_> = (0..5).map(|_| Flow::new(test_config())).collect();
+        for flow in flows.iter() {
+            assert_eq!(ptr.insert(flow.clone()), Ok(()));
+        }
+        assert_eq!(ptr.len(), 5);
+        assert_eq!(ptr.get_pool_size(), Some(8));
+
+        let mut flow_ids: Vec<_> = flows
+            .iter()
+            .map(|flow| flow.read().unwrap().id.to_owned())
+            .collect();
+        flow_ids.sort();
+        let list_ids = |after: Option<&str>, limit: usize| -> Vec<String> {
+            ptr.list(after, limit)
+                .iter()
+                .map(|flow| flow.read().unwrap().id.to_owned())
+                .collect()
+        };
+        assert_eq!(list_ids(None, 100), flow_ids);
+        assert_eq!(list_ids(None, 2), flow_ids[..2].to_vec());
+        assert_eq!(list_ids(Some(flow_ids[1].as_str()), 2), flow_ids[2..4].to_vec());
+        assert_eq!(list_ids(Some(flow_ids[4].as_str()), 2), Vec::<String>::new());
+
+        let flow_ptr = ptr.get(&flow_ids[0]).unwrap();
+        let fut = flow_ptr.read().unwrap().pull(0, None);
+        assert_eq!(ptr.evict(&flow_ids[0]), Ok(()));
+        assert_eq!(ptr.evict(&flow_ids[0]), Err(()));
+        assert_eq!(fut.wait(), Err(flow::Error::Other));
+        assert!(ptr.get(&flow_ids[0]).is_none());
+        assert_eq!(ptr.len(), 4);
+        assert_eq!(ptr.get_evicted(), 1);
+        assert_eq!(list_ids(None, 100), flow_ids[1..].to_vec());
+    }
+
+    #[test]
+    fn usage() {
+        let usage = |buffered_bytes, buffered_chunks| Usage {
+            buffered_bytes,
+            buffered_chunks,
+            ..Default::default()
+        };
+        let ptr = Pool::new(4, None, None);
+        let flow_a = Flow::new(test_config());
+        let flow_b = Flow::new(test_config());
+        flow_a.write().unwrap().push("Hello".into()).wait().unwrap();
+        assert_eq!(ptr.insert(flow_a.clone()), Ok(()));
+        assert_eq!(ptr.insert(flow_b.clone()), Ok(()));
+        assert_eq!(ptr.get_usage(), usage(5, 1));
+
+        flow_b.write().unwrap().push("World!".into()).wait().unwrap();
+        assert_eq!(ptr.get_usage(), usage(11, 2));
+        flow_b.write().unwrap().close().wait().unwrap();
+        assert_eq!(ptr.get_usage(), usage(11, 3));
+
+        // The flow leaves the pool once it is completely pulled.
+        let fut = flow_b.read().unwrap().pull(0, None);
+        assert!(fut.wait().is_ok());
+        let fut = flow_b.read().unwrap().pull(1, None);
+        assert_eq!(fut.wait(), Err(flow::Error::Eof));
+        assert_eq!(ptr.len(), 1);
+        assert_eq!(ptr.get_usage(), usage(5, 1));
+
+        let flow_id = flow_a.read().unwrap().id.to_owned();
+        assert_eq!(ptr.evict(&flow_id), Ok(()));
+        assert_eq!(ptr.get_usage(), usage(0, 0));
+    }
+
+    #[test]
+    fn sweep() {
+        let ptr = Pool::new(4, None, Some(Duration::from_secs(2)));
+        let flow_a = Flow::new(test_config());
+        let flow_b = Flow::new(test_config());
+        let flowa_id = flow_a.read().unwrap().id.to_owned();
+        let flowb_id = flow_b.read().unwrap().id.to_owned();
+        assert_eq!(ptr.insert(flow_a.clone()), Ok(()));
+        assert_eq!(ptr.insert(flow_b.clone()), Ok(()));
+        let fut = flow_a.read().unwrap().pull(0, None);
+        assert_eq!(ptr.sweep(), 0);
+
+        thread::sleep(Duration::from_secs(1));
+        {
+            let fut = flow_b.write().unwrap().push("Hello".into());
+            fut.wait().unwrap();
+        }
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(ptr.sweep(), 1);
+        assert_eq!(fut.wait(), Err(flow::Error::Other));
+        assert!(ptr.get(&flowa_id).is_none());
+        assert!(ptr.get(&flowb_id).is_some());
+
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(ptr.sweep(), 1);
+        assert!(ptr.get(&flowb_id).is_none());
+    }
+
+    #[test]
+    fn flow_timeouts() {
+        let ptr = Pool::new(1, None, None);
+        let new_flow = |ttl, idle_timeout| {
+            Flow::new(flow::Config {
+                ttl,
+                idle_timeout,
+                ..test_config()
+            })
+        };
+        let flow_a = new_flow(Some(1), None);
+        let flow_b = new_flow(None, Some(2));
+        let flow_c = new_flow(None, None);
+        let flowa_id = flow_a.read().unwrap().id.to_owned();
+        let flowb_id = flow_b.read().unwrap().id.to_owned();
+        let flowc_id = flow_c.read().unwrap().id.to_owned();
+        assert_eq!(ptr.insert(flow_c.clone()), Ok(()));
+        assert_eq!(ptr.insert(flow_b.clone()), Ok(()));
+        assert_eq!(ptr.insert(flow_a.clone()), Ok(()));
+
+        thread::sleep(Duration::from_millis(1200));
+        assert_eq!(ptr.sweep(), 1);
+        assert!(ptr.get(&flowa_id).is_none());
+        assert!(ptr.get(&flowb_id).is_some());
+
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(ptr.sweep(), 1);
+        assert!(ptr.get(&flowb_id).is_none());
+        assert!(ptr.get(&flowc_id).is_some());
+    }
 }
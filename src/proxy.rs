@@ -0,0 +1,206 @@
+use futures::{Async, Future, Poll};
+use std::{io, str, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
+use tokio_io::AsyncRead;
+
+/// The longest header of version 1, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+enum Parse {
+    // The bytes to read before parsing again.
+    Incomplete(usize),
+    Done(Option<SocketAddr>),
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    let line = str::from_utf8(line).map_err(|_| invalid("Invalid PROXY header"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match (fields[0], fields.get(1).cloned()) {
+        ("PROXY", Some("UNKNOWN")) => Ok(None),
+        ("PROXY", Some(protocol)) if fields.len() == 6 => {
+            let ip: IpAddr = fields[2]
+                .parse()
+                .map_err(|_| invalid("Invalid PROXY source address"))?;
+            let port: u16 = fields[4]
+                .parse()
+                .map_err(|_| invalid("Invalid PROXY source port"))?;
+            match (protocol, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+                    Ok(Some(SocketAddr::new(ip, port)))
+                }
+                _ => Err(invalid("Invalid PROXY protocol")),
+            }
+        }
+        _ => Err(invalid("Invalid PROXY header")),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    let addrs = &header[V2_HEADER_LEN..];
+    let port = |offset: usize| (addrs[offset] as u16) << 8 | addrs[offset + 1] as u16;
+    match header[12] {
+        // Connections made by the proxy itself, such as health checks.
+        0x20 => Ok(None),
+        0x21 => match header[13] >> 4 {
+            0x1 if addrs.len() >= 12 => {
+                let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+                Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+            }
+            0x2 if addrs.len() >= 36 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addrs[..16]);
+                Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32))))
+            }
+            // Unspecified and Unix domain socket clients have no address to report.
+            0x0 | 0x3 => Ok(None),
+            _ => Err(invalid("Invalid PROXY address family")),
+        },
+        _ => Err(invalid("Invalid PROXY version")),
+    }
+}
+
+/// Parse the header read so far. Versions are told apart by the first byte.
+fn parse(buf: &[u8]) -> Result<Parse, io::Error> {
+    match buf.first() {
+        None => Ok(Parse::Incomplete(1)),
+        Some(&b'P') => {
+            if buf.ends_with(b"\r\n") {
+                parse_v1(&buf[..buf.len() - 2]).map(Parse::Done)
+            } else if buf.len() >= V1_MAX_LEN {
+                Err(invalid("PROXY header too long"))
+            } else {
+                // Read by bytes, so nothing after the header is consumed.
+                Ok(Parse::Incomplete(1))
+            }
+        }
+        Some(&b'\r') => {
+            if buf.len() < V2_HEADER_LEN {
+                return Ok(Parse::Incomplete(V2_HEADER_LEN - buf.len()));
+            }
+            if &buf[..V2_SIGNATURE.len()] != V2_SIGNATURE {
+                return Err(invalid("Invalid PROXY header"));
+            }
+            let len = V2_HEADER_LEN + ((buf[14] as usize) << 8 | buf[15] as usize);
+            if buf.len() < len {
+                Ok(Parse::Incomplete(len - buf.len()))
+            } else {
+                parse_v2(buf).map(Parse::Done)
+            }
+        }
+        Some(_) => Err(invalid("Missing PROXY header")),
+    }
+}
+
+pub struct ReadHeader<T> {
+    io: Option<T>,
+    buf: Vec<u8>,
+}
+
+/// Read the PROXY protocol header, version 1 or 2, sent by a proxy in front of the server.
+/// Resolves to the stream positioned after the header and the address of the client, if the
+/// proxy reports one.
+pub fn read_header<T: AsyncRead>(io: T) -> ReadHeader<T> {
+    ReadHeader {
+        io: Some(io),
+        buf: Vec::new(),
+    }
+}
+
+impl<T: AsyncRead> Future for ReadHeader<T> {
+    type Item = (T, Option<SocketAddr>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let len = match parse(&self.buf)? {
+                Parse::Incomplete(len) => len,
+                Parse::Done(addr) => {
+                    let io = self.io.take().expect("poll after completion");
+                    return Ok(Async::Ready((io, addr)));
+                }
+            };
+            let start = self.buf.len();
+            self.buf.resize(start + len, 0);
+            let ret = self.io
+                .as_mut()
+                .expect("poll after completion")
+                .read(&mut self.buf[start..]);
+            match ret {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.buf.truncate(start + size),
+                Err(err) => {
+                    self.buf.truncate(start);
+                    if err.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(Async::NotReady);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(data: &[u8]) -> Result<(&[u8], Option<SocketAddr>), io::Error> {
+        read_header(data).wait()
+    }
+
+    #[test]
+    fn version_1() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET").unwrap(),
+            (&b"GET"[..], Some("192.0.2.1:56324".parse().unwrap()))
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            (&b""[..], Some("[2001:db8::1]:56324".parse().unwrap()))
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\nGET").unwrap(), (&b"GET"[..], None));
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1\r\n").is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(read(&[b'P'; 200]).is_err());
+        assert!(read(b"PROXY TCP4").is_err());
+    }
+
+    #[test]
+    fn version_2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        header.extend_from_slice(b"GET");
+        assert_eq!(
+            read(&header).unwrap(),
+            (&b"GET"[..], Some("192.0.2.1:56324".parse().unwrap()))
+        );
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        header.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(
+            read(&header).unwrap(),
+            (&b""[..], Some("[2001:db8::1]:56324".parse().unwrap()))
+        );
+
+        // Local connections carry no address, and may have TLVs to skip.
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x03, 1, 2, 3]);
+        header.extend_from_slice(b"GET");
+        assert_eq!(read(&header).unwrap(), (&b"GET"[..], None));
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x31, 0x11, 0x00, 0x00]);
+        assert!(read(&header).is_err());
+        assert!(read(&V2_SIGNATURE[..8]).is_err());
+    }
+}
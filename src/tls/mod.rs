@@ -1,18 +1,248 @@
-#[cfg(target_os = "windows")]
+#[cfg(feature = "tls-rustls")]
+#[path = "rustls.rs"]
+mod imp;
+#[cfg(all(not(feature = "tls-rustls"), target_os = "windows"))]
 #[path = "schannel.rs"]
 mod imp;
-#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+#[cfg(all(not(feature = "tls-rustls"),
+          not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
 #[path = "openssl.rs"]
 mod imp;
-#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+#[cfg(any(feature = "tls-rustls",
+          not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
 pub use self::imp::build_tls_from_pem;
-use native_tls::TlsAcceptor;
-use std::{fs::File, io::Read};
+pub use self::imp::{alpn_protocol, peer_subject, AcceptAsync, Acceptor, TlsStream};
+use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io::Read,
+          sync::{Arc, Mutex, RwLock}, time::SystemTime};
+use tokio_io::{AsyncRead, AsyncWrite};
 
-#[allow(dead_code)]
-pub fn build_tls_from_pfx(pfx_path: &str) -> TlsAcceptor {
-    let mut pfx_file = File::open(pfx_path).unwrap();
+pub fn build_tls_from_pfx(pfx_path: &str) -> Result<Acceptor, String> {
     let mut buf = Vec::new();
-    pfx_file.read_to_end(&mut buf).unwrap();
+    File::open(pfx_path)
+        .and_then(|mut pfx_file| pfx_file.read_to_end(&mut buf))
+        .map_err(|err| format!("{}: {}", pfx_path, err))?;
     imp::build_acceptor_from_pfx(&buf)
 }
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertPair {
+    pub cert: String,
+    pub private: String,
+}
+
+/// Look up the entry of a lower-cased server name, exactly first, then by a `*.` wildcard entry.
+#[cfg(any(feature = "tls-rustls",
+          not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+fn lookup_sni<'a, T>(entries: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    entries.get(name).or_else(|| {
+        name.find('.')
+            .and_then(|pos| entries.get(&format!("*{}", &name[pos..])))
+    })
+}
+
+pub enum TlsSource {
+    Pem {
+        cert_path: String,
+        priv_path: String,
+        sni: BTreeMap<String, CertPair>,
+        client_ca: Option<String>,
+    },
+    Pfx { pfx_path: String },
+}
+
+impl TlsSource {
+    fn paths(&self) -> Vec<&str> {
+        match *self {
+            TlsSource::Pem {
+                ref cert_path,
+                ref priv_path,
+                ref sni,
+                ref client_ca,
+            } => {
+                let mut paths: Vec<&str> = vec![cert_path, priv_path];
+                for pair in sni.values() {
+                    paths.push(&pair.cert);
+                    paths.push(&pair.private);
+                }
+                if let Some(ref client_ca) = *client_ca {
+                    paths.push(client_ca);
+                }
+                paths
+            }
+            TlsSource::Pfx { ref pfx_path } => vec![pfx_path],
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    fn build(&self) -> Result<Acceptor, String> {
+        match *self {
+            #[cfg(any(feature = "tls-rustls",
+                      not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+            TlsSource::Pem {
+                ref cert_path,
+                ref priv_path,
+                ref sni,
+                ref client_ca,
+            } => build_tls_from_pem(
+                cert_path,
+                priv_path,
+                sni,
+                client_ca.as_ref().map(|client_ca| client_ca.as_str()),
+            ),
+            #[cfg(all(not(feature = "tls-rustls"),
+                      any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+            TlsSource::Pem { .. } => Err("PEM certificates are not supported".into()),
+            TlsSource::Pfx { ref pfx_path } => build_tls_from_pfx(pfx_path),
+        }
+    }
+}
+
+/// An `Acceptor` of the TLS backend which can be swapped while the workers keep using it.
+/// Handshakes in progress finish with the acceptor they started with.
+#[derive(Clone)]
+pub struct SharedAcceptor {
+    source: Arc<TlsSource>,
+    acceptor: Arc<RwLock<Acceptor>>,
+    modified: Arc<Mutex<Vec<Option<SystemTime>>>>,
+}
+
+impl SharedAcceptor {
+    pub fn new(source: TlsSource) -> Result<Self, String> {
+        let modified = source.modified();
+        let acceptor = source.build()?;
+        Ok(SharedAcceptor {
+            source: Arc::new(source),
+            acceptor: Arc::new(RwLock::new(acceptor)),
+            modified: Arc::new(Mutex::new(modified)),
+        })
+    }
+
+    pub fn get(&self) -> Acceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    pub fn accept_async<S: AsyncRead + AsyncWrite>(&self, io: S) -> AcceptAsync<S> {
+        imp::accept_async(&self.get(), io)
+    }
+
+    /// Rebuild the acceptor from the source. The current one is kept on failure.
+    pub fn reload(&self) -> Result<(), String> {
+        let modified = self.source.modified();
+        let result = self.source.build();
+        // Remember the failed files as well, so a broken certificate is reported once.
+        *self.modified.lock().unwrap() = modified;
+        *self.acceptor.write().unwrap() = result?;
+        Ok(())
+    }
+
+    /// Reload only if any of the source files is changed since the last load.
+    pub fn reload_if_modified(&self) -> Result<bool, String> {
+        if *self.modified.lock().unwrap() == self.source.modified() {
+            return Ok(false);
+        }
+        self.reload().map(|_| true)
+    }
+}
+
+#[cfg(all(test,
+          any(feature = "tls-rustls",
+              not(any(target_os = "windows", target_os = "macos", target_os = "ios")))))]
+mod tests {
+    use super::*;
+    use std::{env, thread, time::Duration};
+
+    #[test]
+    fn reload() {
+        let dir = env::temp_dir().join(format!("furakus-tls-{}", ::uuid::Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let priv_path = dir.join("private.pem");
+        fs::copy("./tests/cert.pem", &cert_path).unwrap();
+        fs::copy("./tests/private.pem", &priv_path).unwrap();
+
+        let acceptor = SharedAcceptor::new(TlsSource::Pem {
+            cert_path: cert_path.to_str().unwrap().to_owned(),
+            priv_path: priv_path.to_str().unwrap().to_owned(),
+            sni: BTreeMap::new(),
+            client_ca: None,
+        }).unwrap();
+        assert_eq!(acceptor.reload_if_modified(), Ok(false));
+        assert_eq!(acceptor.reload(), Ok(()));
+
+        // Make sure the modification time moves forward.
+        thread::sleep(Duration::from_millis(1100));
+        fs::write(&cert_path, "broken").unwrap();
+        assert!(acceptor.reload_if_modified().is_err());
+        assert_eq!(acceptor.reload_if_modified(), Ok(false));
+        acceptor.get();
+
+        thread::sleep(Duration::from_millis(1100));
+        fs::copy("./tests/cert.pem", &cert_path).unwrap();
+        assert_eq!(acceptor.reload_if_modified(), Ok(true));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(acceptor.reload().is_err());
+    }
+
+    #[test]
+    fn sni() {
+        let pair = CertPair {
+            cert: "./tests/cert.pem".into(),
+            private: "./tests/private.pem".into(),
+        };
+        let mut sni = BTreeMap::new();
+        sni.insert("example.com".to_owned(), pair.clone());
+        sni.insert("*.example.org".to_owned(), pair.clone());
+        assert!(build_tls_from_pem(&pair.cert, &pair.private, &sni, None).is_ok());
+
+        sni.insert(
+            "example.net".to_owned(),
+            CertPair {
+                cert: "./tests/missing.pem".into(),
+                private: "./tests/private.pem".into(),
+            },
+        );
+        assert!(build_tls_from_pem(&pair.cert, &pair.private, &sni, None).is_err());
+    }
+
+    #[test]
+    fn client_ca() {
+        let build = |client_ca| {
+            build_tls_from_pem(
+                "./tests/cert.pem",
+                "./tests/private.pem",
+                &BTreeMap::new(),
+                Some(client_ca),
+            )
+        };
+        if cfg!(feature = "tls-rustls") {
+            assert!(build("./tests/cert.pem").is_err());
+            return;
+        }
+        assert!(build("./tests/cert.pem").is_ok());
+        assert!(build("./tests/missing.pem").is_err());
+    }
+
+    #[test]
+    fn ecdsa() {
+        let result = build_tls_from_pem(
+            "./tests/ec-cert.pem",
+            "./tests/ec-private.pem",
+            &BTreeMap::new(),
+            None,
+        );
+        if cfg!(feature = "tls-rustls") {
+            let err = result.err().unwrap();
+            assert!(err.contains("ECDSA keys are not supported"), "{}", err);
+            return;
+        }
+        assert!(result.is_ok());
+    }
+}
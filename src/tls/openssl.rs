@@ -1,35 +1,144 @@
 extern crate openssl;
 
-use native_tls::{TlsAcceptor, TlsAcceptorBuilder, backend::openssl::TlsAcceptorBuilderExt};
-use self::openssl::{pkcs12::Pkcs12, pkey::PKey, ssl::{SslAcceptorBuilder, SslMethod}, x509::X509};
-use std::{fs::File, io::Read};
+use native_tls::{TlsAcceptor, TlsAcceptorBuilder,
+                 backend::openssl::{TlsAcceptorBuilderExt, TlsStreamExt}};
+use self::openssl::{error::ErrorStack, nid, pkcs12::Pkcs12, pkey::PKey,
+                    ssl::{SslAcceptorBuilder, SslContext, SslContextBuilder, SslMethod,
+                          SSL_VERIFY_PEER},
+                    x509::X509};
+use std::{collections::{BTreeMap, HashMap}, fs::File, io::Read};
+use super::{lookup_sni, CertPair};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::{self, TlsAcceptorExt};
 
-fn build_acceptor(privkey: &PKey, cert: &X509, chain: &[X509]) -> TlsAcceptor {
-    let builder =
-        SslAcceptorBuilder::mozilla_modern(SslMethod::tls(), privkey, cert, chain).unwrap();
-    TlsAcceptorBuilder::from_openssl(builder).build().unwrap()
+pub type Acceptor = TlsAcceptor;
+pub type TlsStream<S> = tokio_tls::TlsStream<S>;
+pub type AcceptAsync<S> = tokio_tls::AcceptAsync<S>;
+
+pub fn accept_async<S: AsyncRead + AsyncWrite>(acceptor: &Acceptor, io: S) -> AcceptAsync<S> {
+    acceptor.accept_async(io)
+}
+
+/// Offer HTTP/2 to the clients by ALPN.
+fn set_alpn(builder: &mut SslContextBuilder) -> Result<(), String> {
+    builder
+        .set_alpn_protocols(&[b"h2" as &[u8], b"http/1.1"])
+        .map_err(|err| err.to_string())
+}
+
+fn build_acceptor(privkey: &PKey, cert: &X509, chain: &[X509]) -> Result<TlsAcceptor, String> {
+    let mut builder = SslAcceptorBuilder::mozilla_modern(SslMethod::tls(), privkey, cert, chain)
+        .map_err(|err| err.to_string())?;
+    set_alpn(builder.builder_mut())?;
+    TlsAcceptorBuilder::from_openssl(builder)
+        .build()
+        .map_err(|err| err.to_string())
 }
 
-pub fn build_acceptor_from_pfx(buf: &[u8]) -> TlsAcceptor {
-    let depkcs12 = Pkcs12::from_der(buf).unwrap().parse("").unwrap();
+pub fn build_acceptor_from_pfx(buf: &[u8]) -> Result<TlsAcceptor, String> {
+    let depkcs12 = Pkcs12::from_der(buf)
+        .and_then(|pkcs12| pkcs12.parse(""))
+        .map_err(|err| err.to_string())?;
     let chain = depkcs12.chain.into_iter().collect::<Vec<_>>();
     build_acceptor(&depkcs12.pkey, &depkcs12.cert, &chain)
 }
 
-#[allow(dead_code)]
-pub fn build_tls_from_pem(cert_path: &str, priv_path: &str) -> TlsAcceptor {
-    let fullchain = {
-        let mut cert_file = File::open(cert_path).unwrap();
-        let mut buf = Vec::new();
-        cert_file.read_to_end(&mut buf).unwrap();
-        X509::stack_from_pem(&buf).unwrap()
-    };
-    let privkey = {
-        let mut priv_file = File::open(priv_path).unwrap();
-        let mut buf = Vec::new();
-        priv_file.read_to_end(&mut buf).unwrap();
-        PKey::private_key_from_pem(&buf).unwrap()
-    };
-    let cert = fullchain.first().unwrap();
-    build_acceptor(&privkey, cert, &fullchain[1..])
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    Ok(buf)
+}
+
+fn load_pem(cert_path: &str, priv_path: &str) -> Result<(PKey, Vec<X509>), String> {
+    let fullchain = X509::stack_from_pem(&read_file(cert_path)?)
+        .map_err(|err| format!("{}: {}", cert_path, err))?;
+    let privkey = PKey::private_key_from_pem(&read_file(priv_path)?)
+        .map_err(|err| format!("{}: {}", priv_path, err))?;
+    if fullchain.is_empty() {
+        return Err(format!("{}: No certificate", cert_path));
+    }
+    Ok((privkey, fullchain))
+}
+
+/// Ask for a client certificate and verify it against the CA. Clients without one are still
+/// accepted, and left to the other means of authorization.
+fn set_client_ca(builder: &mut SslContextBuilder, client_ca: Option<&str>) -> Result<(), String> {
+    if let Some(client_ca) = client_ca {
+        builder
+            .set_ca_file(client_ca)
+            .map_err(|err| format!("{}: {}", client_ca, err))?;
+        builder.set_verify(SSL_VERIFY_PEER);
+    }
+    Ok(())
+}
+
+fn build_context(pair: &CertPair, client_ca: Option<&str>) -> Result<SslContext, String> {
+    let (privkey, fullchain) = load_pem(&pair.cert, &pair.private)?;
+    let map_err = |err: ErrorStack| format!("{}: {}", pair.cert, err);
+    let mut builder = SslContext::builder(SslMethod::tls()).map_err(&map_err)?;
+    builder.set_private_key(&privkey).map_err(&map_err)?;
+    builder.set_certificate(&fullchain[0]).map_err(&map_err)?;
+    for cert in fullchain[1..].iter() {
+        builder
+            .add_extra_chain_cert(cert.clone())
+            .map_err(&map_err)?;
+    }
+    builder.check_private_key().map_err(&map_err)?;
+    set_client_ca(&mut builder, client_ca)?;
+    set_alpn(&mut builder)?;
+    Ok(builder.build())
+}
+
+/// Build an acceptor serving the default certificate, or the certificate of the server name
+/// requested by SNI. Names are matched exactly first, then by a `*.` wildcard entry.
+pub fn build_tls_from_pem(
+    cert_path: &str,
+    priv_path: &str,
+    sni: &BTreeMap<String, CertPair>,
+    client_ca: Option<&str>,
+) -> Result<TlsAcceptor, String> {
+    let (privkey, fullchain) = load_pem(cert_path, priv_path)?;
+    let mut builder = SslAcceptorBuilder::mozilla_modern(
+        SslMethod::tls(),
+        &privkey,
+        &fullchain[0],
+        &fullchain[1..],
+    ).map_err(|err| err.to_string())?;
+    set_client_ca(builder.builder_mut(), client_ca)?;
+    set_alpn(builder.builder_mut())?;
+    if !sni.is_empty() {
+        let mut contexts = HashMap::new();
+        for (name, pair) in sni.iter() {
+            contexts.insert(name.to_lowercase(), build_context(pair, client_ca)?);
+        }
+        builder.builder_mut().set_servername_callback(move |ssl| {
+            let context = ssl.servername()
+                .map(|name| name.to_lowercase())
+                .and_then(|name| lookup_sni(&contexts, &name));
+            // Unknown names fall back to the default certificate.
+            if let Some(context) = context {
+                ssl.set_ssl_context(context).ok();
+            }
+            Ok(())
+        });
+    }
+    TlsAcceptorBuilder::from_openssl(builder)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+/// The common name of the verified client certificate.
+pub fn peer_subject<S>(stream: &TlsStream<S>) -> Option<String> {
+    let cert = stream.get_ref().raw_stream().ssl().peer_certificate()?;
+    let entry = cert.subject_name().entries_by_nid(nid::COMMONNAME).next()?;
+    entry.data().as_utf8().ok().map(|name| name.to_string())
+}
+
+/// The protocol negotiated by ALPN.
+pub fn alpn_protocol<S>(stream: &TlsStream<S>) -> Option<String> {
+    let ssl = stream.get_ref().raw_stream().ssl();
+    ssl.selected_alpn_protocol()
+        .map(|protocol| String::from_utf8_lossy(protocol).into_owned())
 }
@@ -1,8 +1,30 @@
 use native_tls::{Pkcs12, Protocol, TlsAcceptor};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::{self, TlsAcceptorExt};
 
-pub fn build_acceptor_from_pfx(buf: &[u8]) -> TlsAcceptor {
-    let pkcs12 = Pkcs12::from_der(&buf, "").unwrap();
-    let mut builder = TlsAcceptor::builder(pkcs12).unwrap();
-    builder.supported_protocols(&[Protocol::Tlsv12]).unwrap();
-    builder.build().unwrap()
+pub type Acceptor = TlsAcceptor;
+pub type TlsStream<S> = tokio_tls::TlsStream<S>;
+pub type AcceptAsync<S> = tokio_tls::AcceptAsync<S>;
+
+pub fn accept_async<S: AsyncRead + AsyncWrite>(acceptor: &Acceptor, io: S) -> AcceptAsync<S> {
+    acceptor.accept_async(io)
+}
+
+pub fn build_acceptor_from_pfx(buf: &[u8]) -> Result<TlsAcceptor, String> {
+    let pkcs12 = Pkcs12::from_der(&buf, "").map_err(|err| err.to_string())?;
+    let mut builder = TlsAcceptor::builder(pkcs12).map_err(|err| err.to_string())?;
+    builder
+        .supported_protocols(&[Protocol::Tlsv12])
+        .map_err(|err| err.to_string())?;
+    builder.build().map_err(|err| err.to_string())
+}
+
+/// Client certificates are only supported by the openssl backend.
+pub fn peer_subject<S>(_stream: &TlsStream<S>) -> Option<String> {
+    None
+}
+
+/// ALPN is only supported by the openssl and rustls backends.
+pub fn alpn_protocol<S>(_stream: &TlsStream<S>) -> Option<String> {
+    None
 }